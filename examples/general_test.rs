@@ -1,4 +1,4 @@
-use project::{*, graph::*};
+use project::{*, graph::*, node::{NodeKind, OscillatorNode, MultNode, OutputNode}};
 use bevy::{prelude::*, ecs::schedule::{ScheduleBuildSettings, LogLevel}};
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
 
@@ -26,11 +26,17 @@ fn setup(mut _commands: Commands) {
 }
 
 fn on_n_press(mut commands: Commands, input: Res<Input<KeyCode>>) {
-    if input.just_pressed(KeyCode::N) {
-        commands.spawn((
-            VertexBundle::new((0.0, 0.0, 1.0).into(), "Alice", 20.0),
-            BlankVertex
-        ));
+    for (key, kind) in [
+        (KeyCode::N, NodeKind::new(OscillatorNode)),
+        (KeyCode::M, NodeKind::new(MultNode)),
+        (KeyCode::O, NodeKind::new(OutputNode)),
+    ] {
+        if input.just_pressed(key) {
+            commands.spawn((
+                VertexBundle::new((0.0, 0.0, 1.0).into(), kind.name(), VERTEX_HALF_EXTEND),
+                kind
+            ));
+        }
     }
 }
 
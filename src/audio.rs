@@ -1,44 +1,232 @@
-use std::time::Duration;
+use std::{fs, io, time::Duration, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}, mpsc::{self, Receiver}}};
 
-use bevy::{prelude::{Plugin, World, Resource, Deref, DerefMut, IntoSystemSetConfig, StartupSet, IntoSystemConfig}, tasks::IoTaskPool};
+use bevy::{prelude::{Plugin, World, Resource, Res, EventWriter, Deref, DerefMut, IntoSystemSetConfig, StartupSet, IntoSystemConfig, CoreSet, Events, warn}, tasks::IoTaskPool};
+use cpal::traits::{HostTrait, DeviceTrait};
 use knyst::{
-    audio_backend::{CpalBackend, CpalBackendOptions}, 
-    prelude::{AudioBackend, Graph, GraphSettings, RunGraphSettings}, 
-    controller::KnystCommands
+    audio_backend::{CpalBackend, CpalBackendOptions, AudioBackendError},
+    prelude::{AudioBackend, Graph, GraphSettings, RunGraphSettings},
+    controller::KnystCommands,
+    KnystError,
 };
+use serde::{Serialize, Deserialize};
 
-use crate::AppSet;
+use crate::{AppSet, ui::Notifications};
 
+mod offline;
+
+pub use offline::OfflineBackend;
+
+pub const AUDIO_SETTINGS_PATH: &str = "audio_settings.json";
 
 pub struct KnystAudioPlugin;
 
 impl Plugin for KnystAudioPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
-        app .configure_set(AppSet::AudioStartup.in_base_set(StartupSet::Startup))
-            .add_startup_system(setup_knyst_graph.in_set(AppSet::AudioStartup));
+        if !app.world.contains_resource::<AudioSettings>() {
+            app.insert_resource(AudioSettings::load());
+        }
+        app .add_event::<RestartAudio>()
+            .add_event::<AudioRestarted>()
+            .add_event::<AudioError>()
+            .configure_set(AppSet::AudioStartup.in_base_set(StartupSet::Startup))
+            .add_startup_system(setup_knyst_graph.in_set(AppSet::AudioStartup))
+            .add_system(restart_audio.in_base_set(CoreSet::PreUpdate))
+            .add_system(forward_audio_errors.in_base_set(CoreSet::PreUpdate).after(restart_audio));
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AudioBackendKind {
+    #[default]
+    Cpal,
+    // Processes blocks on a timer, without a sound card
+    OfflineTimer,
+    // Only processes blocks when `OfflineBackend::process_block` is called
+    OfflineManual,
+}
+
+impl AudioBackendKind {
+    pub const ALL: [AudioBackendKind; 3] = [AudioBackendKind::Cpal, AudioBackendKind::OfflineTimer, AudioBackendKind::OfflineManual];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AudioBackendKind::Cpal => "Sound card",
+            AudioBackendKind::OfflineTimer => "Offline (timer)",
+            AudioBackendKind::OfflineManual => "Offline (manual)",
+        }
+    }
+}
+
+// Insert before adding `KnystAudioPlugin` to override the settings file
+#[derive(Resource, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AudioSettings {
+    #[serde(default)]
+    pub backend: AudioBackendKind,
+    pub device: String,
+    // `None` uses the device's own sample rate
+    pub sample_rate: Option<u32>,
+    pub block_size: usize,
+    pub num_outputs: usize,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        AudioSettings {
+            backend: AudioBackendKind::Cpal,
+            device: "default".to_string(),
+            sample_rate: None,
+            block_size: 64,
+            num_outputs: 2,
+        }
     }
 }
 
+impl AudioSettings {
+    /// Reads the settings from `AUDIO_SETTINGS_PATH`, falling back to the defaults.
+    pub fn load() -> Self {
+        fs::read_to_string(AUDIO_SETTINGS_PATH)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        fs::write(AUDIO_SETTINGS_PATH, serde_json::to_string_pretty(self)?)
+    }
+}
+
+pub fn output_device_names() -> Vec<String> {
+    cpal::default_host()
+        .output_devices()
+        .map(|devices| devices.filter_map(|device| device.name().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// The configuration the audio graph is actually running with.
+#[derive(Resource, Debug, Clone, PartialEq)]
+pub struct AudioStatus {
+    pub backend: AudioBackendKind,
+    pub sample_rate: usize,
+    pub block_size: usize,
+    pub num_outputs: usize,
+}
+
+/// Send to tear down the audio backend and start it again with the current `AudioSettings`.
+pub struct RestartAudio;
+
+/// Sent once a restarted audio graph is running; every node has to be pushed again.
+pub struct AudioRestarted;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioErrorKind {
+    Connection,
+    Push,
+    Free,
+    Schedule,
+    Other,
+}
+
+/// An error reported by the audio engine while running the graph.
+#[derive(Debug, Clone)]
+pub struct AudioError {
+    pub kind: AudioErrorKind,
+    pub message: String,
+}
+
+impl From<KnystError> for AudioError {
+    fn from(e: KnystError) -> Self {
+        let kind = match e {
+            KnystError::ConnectionError(_) => AudioErrorKind::Connection,
+            KnystError::PushError(_) => AudioErrorKind::Push,
+            KnystError::FreeError(_) => AudioErrorKind::Free,
+            KnystError::ScheduleError(_) => AudioErrorKind::Schedule,
+            _ => AudioErrorKind::Other,
+        };
+        AudioError { kind, message: e.to_string() }
+    }
+}
+
+// Errors are sent from the controller's thread, and turned into events on the main thread
+#[derive(Resource)]
+struct AudioErrorReceiver(Mutex<Receiver<AudioError>>);
+
+#[derive(Resource)]
+struct ControllerHandle(Arc<AtomicBool>);
+
+trait StoppableBackend {
+    fn stop(&mut self) -> Result<(), AudioBackendError>;
+}
+
+impl<B: AudioBackend> StoppableBackend for B {
+    fn stop(&mut self) -> Result<(), AudioBackendError> {
+        AudioBackend::stop(self)
+    }
+}
+
+struct Backend(Box<dyn StoppableBackend>);
+
 fn setup_knyst_graph(world: &mut World) {
-    let mut backend = CpalBackend::new(CpalBackendOptions::default()).expect("Error in cpal audio backend");
-    let num_outputs = backend.num_outputs();
-    let sample_rate = backend.sample_rate() as f32;
-    let block_size = backend.block_size().unwrap_or(64);
+    let settings = world.resource::<AudioSettings>().clone();
+    let result = match settings.backend {
+        AudioBackendKind::Cpal => CpalBackend::new(CpalBackendOptions {
+            device: settings.device.clone(),
+            ..Default::default()
+        }).and_then(|backend| start_knyst_graph(world, backend, AudioBackendKind::Cpal, &settings)),
+        kind => start_offline_graph(world, kind, &settings),
+    };
+    if let Err(e) = result {
+        warn!("Error in {} audio backend, falling back to offline audio: {e:?}", settings.backend.name());
+        if let Some(mut notifications) = world.get_resource_mut::<Notifications>() {
+            notifications.push(format!("{} unavailable, using offline audio: {e:?}", settings.backend.name()));
+        }
+        start_offline_graph(world, AudioBackendKind::OfflineTimer, &settings).expect("Error in offline audio backend");
+    }
+}
+
+fn start_offline_graph(world: &mut World, kind: AudioBackendKind, settings: &AudioSettings) -> Result<(), AudioBackendError> {
+    let sample_rate = settings.sample_rate.unwrap_or(44100) as usize;
+    let backend = OfflineBackend::new(sample_rate, settings.block_size, settings.num_outputs, kind == AudioBackendKind::OfflineTimer);
+    // The clone shares the running graph, exposing its rendered blocks to systems
+    let shared = backend.clone();
+    start_knyst_graph(world, backend, kind, settings)?;
+    world.insert_resource(shared);
+    Ok(())
+}
+
+fn start_knyst_graph<B: AudioBackend + 'static>(
+    world: &mut World,
+    mut backend: B,
+    kind: AudioBackendKind,
+    settings: &AudioSettings,
+) -> Result<(), AudioBackendError> {
+    let num_outputs = settings.num_outputs.min(backend.num_outputs());
+    let sample_rate = backend.sample_rate();
+    let block_size = backend.block_size().unwrap_or(settings.block_size);
+    if settings.sample_rate.map_or(false, |rate| rate as usize != sample_rate) {
+        // Only reachable with a hand-edited settings file, the settings panel doesn't offer it
+        warn!("{} doesn't support choosing a sample rate, running at {sample_rate} Hz", settings.device);
+    }
+    let (error_sender, error_receiver) = mpsc::channel();
     let resources = knyst::Resources::new(knyst::ResourcesSettings::default());
-    let graph = Graph::new(GraphSettings { block_size, sample_rate, num_outputs, ..Default::default()});
+    let graph = Graph::new(GraphSettings { block_size, sample_rate: sample_rate as f32, num_outputs, ..Default::default()});
     let mut controller = backend
         .start_processing_return_controller(
-            graph, 
-            resources, 
+            graph,
+            resources,
             RunGraphSettings {
                 ..Default::default()
-            }, 
-            knyst::controller::print_error_handler
-        ).expect("Error in audio backend");
+            },
+            move |e: KnystError| {
+                // The receiver is gone once the audio has been restarted
+                let _ = error_sender.send(AudioError::from(e));
+            }
+        )?;
     let commands = controller.get_knyst_commands();
-    
+
+    let running = Arc::new(AtomicBool::new(true));
+    let controller_running = running.clone();
     IoTaskPool::get().spawn(async move {
-        loop {
+        while controller_running.load(Ordering::Relaxed) {
             while !controller.run(300) {}
             std::thread::sleep(Duration::from_micros(1));
         }
@@ -47,7 +235,43 @@ fn setup_knyst_graph(world: &mut World) {
     let commands = AudioCommands(commands);
 
     world.insert_resource(commands);
-    world.insert_non_send_resource(backend);
+    world.insert_resource(ControllerHandle(running));
+    world.insert_resource(AudioErrorReceiver(Mutex::new(error_receiver)));
+    world.insert_resource(AudioStatus { backend: kind, sample_rate, block_size, num_outputs });
+    world.insert_non_send_resource(Backend(Box::new(backend)));
+    Ok(())
+}
+
+fn stop_knyst_graph(world: &mut World) {
+    if let Some(ControllerHandle(running)) = world.remove_resource::<ControllerHandle>() {
+        running.store(false, Ordering::Relaxed);
+    }
+    if let Some(mut backend) = world.remove_non_send_resource::<Backend>() {
+        if let Err(e) = backend.0.stop() {
+            warn!("Error stopping audio backend: {e:?}");
+        }
+    }
+    world.remove_resource::<AudioCommands>();
+    world.remove_resource::<AudioErrorReceiver>();
+    world.remove_resource::<OfflineBackend>();
+}
+
+fn restart_audio(world: &mut World) {
+    let mut events = world.resource_mut::<Events<RestartAudio>>();
+    if events.is_empty() { return; }
+    events.clear();
+
+    stop_knyst_graph(world);
+    setup_knyst_graph(world);
+    world.send_event(AudioRestarted);
+}
+
+fn forward_audio_errors(receiver: Option<Res<AudioErrorReceiver>>, mut errors: EventWriter<AudioError>) {
+    let Some(receiver) = receiver else { return };
+    for error in receiver.0.lock().unwrap().try_iter() {
+        warn!("Audio error: {}", error.message);
+        errors.send(error);
+    }
 }
 
 #[derive(Resource, Deref, DerefMut)]
@@ -0,0 +1,118 @@
+use std::{thread, time::{Duration, Instant}, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}};
+
+use bevy::prelude::Resource;
+use knyst::{
+    audio_backend::AudioBackendError,
+    controller::Controller,
+    graph::RunGraph,
+    prelude::{AudioBackend, Graph, RunGraphSettings},
+    KnystError, Resources,
+};
+
+/// An audio backend that doesn't need a sound card.
+///
+/// With `realtime` set, blocks are processed on a timer at the pace a device would request them.
+/// Otherwise nothing is processed until `process_block` is called.
+#[derive(Resource, Clone)]
+pub struct OfflineBackend {
+    sample_rate: usize,
+    block_size: usize,
+    num_outputs: usize,
+    realtime: bool,
+    run_graph: Arc<Mutex<Option<RunGraph>>>,
+    last_block: Arc<Mutex<Vec<Vec<f32>>>>,
+    running: Arc<AtomicBool>,
+}
+
+impl OfflineBackend {
+    pub fn new(sample_rate: usize, block_size: usize, num_outputs: usize, realtime: bool) -> Self {
+        OfflineBackend {
+            sample_rate,
+            block_size,
+            num_outputs,
+            realtime,
+            run_graph: Arc::new(Mutex::new(None)),
+            last_block: Arc::new(Mutex::new(vec![vec![0.0; block_size]; num_outputs])),
+            running: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Processes one block of the graph and returns it, one `Vec` per output channel.
+    pub fn process_block(&self) -> Option<Vec<Vec<f32>>> {
+        let mut run_graph = self.run_graph.lock().unwrap();
+        let block = process_block(run_graph.as_mut()?, self.num_outputs, self.block_size);
+        *self.last_block.lock().unwrap() = block.clone();
+        Some(block)
+    }
+
+    /// The most recently processed block, one `Vec` per output channel.
+    pub fn last_block(&self) -> Vec<Vec<f32>> {
+        self.last_block.lock().unwrap().clone()
+    }
+}
+
+fn process_block(run_graph: &mut RunGraph, num_outputs: usize, block_size: usize) -> Vec<Vec<f32>> {
+    run_graph.run_resources_communication(50);
+    run_graph.process_block();
+    let output = run_graph.graph_output_buffers();
+    (0..num_outputs)
+        .map(|channel| (0..block_size).map(|sample| output.read(channel, sample)).collect())
+        .collect()
+}
+
+impl AudioBackend for OfflineBackend {
+    fn start_processing_return_controller(
+        &mut self,
+        mut graph: Graph,
+        resources: Resources,
+        run_graph_settings: RunGraphSettings,
+        error_handler: impl FnMut(KnystError) + Send + 'static,
+    ) -> Result<Controller, AudioBackendError> {
+        let (run_graph, resources_command_sender, resources_command_receiver) =
+            RunGraph::new(&mut graph, resources, run_graph_settings)?;
+        let controller = Controller::new(graph, error_handler, resources_command_sender, resources_command_receiver);
+        *self.run_graph.lock().unwrap() = Some(run_graph);
+        self.running.store(true, Ordering::Relaxed);
+
+        if self.realtime {
+            let run_graph = self.run_graph.clone();
+            let last_block = self.last_block.clone();
+            let running = self.running.clone();
+            let (num_outputs, block_size) = (self.num_outputs, self.block_size);
+            let block_duration = Duration::from_secs_f64(block_size as f64 / self.sample_rate as f64);
+            thread::spawn(move || {
+                let mut next_block = Instant::now();
+                while running.load(Ordering::Relaxed) {
+                    if let Some(run_graph) = run_graph.lock().unwrap().as_mut() {
+                        *last_block.lock().unwrap() = process_block(run_graph, num_outputs, block_size);
+                    }
+                    next_block += block_duration;
+                    thread::sleep(next_block.saturating_duration_since(Instant::now()));
+                }
+            });
+        }
+        Ok(controller)
+    }
+
+    fn stop(&mut self) -> Result<(), AudioBackendError> {
+        self.running.store(false, Ordering::Relaxed);
+        *self.run_graph.lock().unwrap() = None;
+        Ok(())
+    }
+
+    fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    fn block_size(&self) -> Option<usize> {
+        Some(self.block_size)
+    }
+
+    fn num_outputs(&self) -> usize {
+        self.num_outputs
+    }
+
+    fn num_inputs(&self) -> usize {
+        0
+    }
+}
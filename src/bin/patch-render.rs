@@ -0,0 +1,57 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::Parser;
+use project::{node_registry, render::{render_patch_file_to_wav, BitDepth, RenderOptions}};
+
+/// Renders a saved patch to a WAV file without opening a window or an audio device.
+#[derive(Parser)]
+#[command(name = "patch-render")]
+struct Args {
+    /// The patch file to render
+    patch: PathBuf,
+    /// The WAV file to write, defaults to the patch path with a `.wav` extension
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Length of the render in seconds
+    #[arg(short, long, default_value_t = 5.0)]
+    duration: f32,
+    #[arg(short, long, default_value_t = 44100)]
+    sample_rate: usize,
+    /// At least 2, since Output nodes play in stereo
+    #[arg(short, long, default_value_t = 2)]
+    channels: usize,
+    /// 16, 24 or 32 (float)
+    #[arg(short, long, default_value = "16", value_parser = parse_bit_depth)]
+    bit_depth: BitDepth,
+}
+
+fn parse_bit_depth(s: &str) -> Result<BitDepth, String> {
+    match s {
+        "16" => Ok(BitDepth::Int16),
+        "24" => Ok(BitDepth::Int24),
+        "32" => Ok(BitDepth::Float32),
+        _ => Err(format!("unsupported bit depth {s}, expected 16, 24 or 32")),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let output = args.output.unwrap_or_else(|| args.patch.with_extension("wav"));
+    let options = RenderOptions {
+        duration: args.duration,
+        sample_rate: args.sample_rate,
+        num_channels: args.channels,
+        bit_depth: args.bit_depth,
+    };
+
+    match render_patch_file_to_wav(&args.patch, &node_registry(), &options, &output) {
+        Ok(()) => {
+            println!("Rendered {} to {}", args.patch.display(), output.display());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Could not render {}: {e}", args.patch.display());
+            ExitCode::FAILURE
+        }
+    }
+}
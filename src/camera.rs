@@ -1,8 +1,10 @@
 use bevy::prelude::*;
 use bevy::math::Vec3Swizzles;
+use bevy::input::mouse::{MouseWheel, MouseScrollUnit};
+use bevy_egui::EguiContexts;
 
 use crate::helper::LastPrimaryCursorPos;
-use crate::{AppSet, graph::GraphSelection, ui::egui_unfocused};
+use crate::{AppSet, Mode, graph::{Graph, GraphSelection, Vertex, VertexArea}, ui::egui_unfocused};
 
 pub struct CameraPlugin;
 
@@ -13,18 +15,60 @@ impl Plugin for CameraPlugin {
                 AppSet::CameraStartup.in_base_set(StartupSet::Startup),
                 AppSet::Camera.in_base_set(CoreSet::Update)
             ))
+            .add_event::<FrameCamera>()
             .add_startup_system(setup.in_set(AppSet::CameraStartup))
-            .add_system((
-                pan_camera
-                    .run_if(not(resource_exists::<GraphSelection>()))
-                    .run_if(egui_unfocused)
-            ).in_set(AppSet::Camera));
+            .add_systems((
+                pan_camera,
+                zoom_camera,
+                frame_camera_keys,
+            )
+                .distributive_run_if(egui_unfocused)
+                .in_set(AppSet::Camera)
+            )
+            .add_systems((
+                frame_camera,
+                animate_camera
+                    .run_if(resource_exists::<CameraTarget>()),
+            )
+                .chain()
+                .after(AppSet::Ui)
+                .in_set(AppSet::Camera)
+            );
     }
 }
 
 #[derive(Component)]
 pub struct PrimaryCamera;
 
+// Limits on the projection scale; smaller is zoomed further in
+pub const MIN_ZOOM: f32 = 0.25;
+pub const MAX_ZOOM: f32 = 5.0;
+
+// Screen pixels left around framed vertices
+const FRAME_PADDING: f32 = 60.0;
+// Fraction of the remaining distance to a framing target covered per second
+const FRAME_SPEED: f32 = 8.0;
+
+// How much one line of scrolling changes the zoom
+const ZOOM_STEP: f32 = 1.1;
+// Pixel scroll deltas (trackpads) per line
+const PIXELS_PER_LINE: f32 = 40.0;
+
+/// Moves and zooms the camera to fit vertices on screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameCamera {
+    All,
+    // Falls back to all vertices when nothing is selected
+    Selection,
+}
+
+// Where the camera is animating to; removed once it arrives or the user pans or zooms
+#[derive(Resource, Debug, Clone, Copy)]
+struct CameraTarget {
+    translation: Vec2,
+    scale: f32,
+}
+
 fn setup(mut commands: Commands) {
     commands.spawn((
         Camera2dBundle::default(),
@@ -33,18 +77,28 @@ fn setup(mut commands: Commands) {
 }
 
 fn pan_camera(
+    mut commands: Commands,
     mut camera: Query<(&Camera, &mut Transform), With<PrimaryCamera>>,
     camera_transform: Query<&mut GlobalTransform, With<PrimaryCamera>>,
     input: Res<Input<MouseButton>>,
+    mode: Res<State<Mode>>,
+    selection: Option<Res<GraphSelection>>,
     last_cursor_pos: Res<LastPrimaryCursorPos>,
     mut camera_diff: Local<Vec2>,  
     mut camera_click_global_transform: Local<GlobalTransform>,  
 ) {
-    if input.just_pressed(MouseButton::Left) {
+    // Left dragging empty canvas box-selects while editing, so it only pans in interact mode
+    let buttons: &[MouseButton] = if mode.0 == Mode::Interact && selection.is_none() {
+        &[MouseButton::Middle, MouseButton::Left]
+    } else {
+        &[MouseButton::Middle]
+    };
+
+    if input.any_just_pressed(buttons.iter().copied()) {
         *camera_diff = Vec2::ZERO;
         *camera_click_global_transform = camera_transform.single().clone();
     }
-    else if input.pressed(MouseButton::Left) {
+    else if input.any_pressed(buttons.iter().copied()) {
         let Some(last_cursor_pos) = last_cursor_pos.0 else { return };
         let (camera, mut camera_transform) = camera.single_mut();
 
@@ -55,8 +109,144 @@ fn pan_camera(
             *camera_diff = camera_transform.translation.xy() + world_cursor_pos;
         }
 
+        commands.remove_resource::<CameraTarget>();
         let new_transform = *camera_diff - world_cursor_pos;
         camera_transform.translation.x = new_transform.x;
         camera_transform.translation.y = new_transform.y;
     }
-}
\ No newline at end of file
+}
+
+// Scales the projection by `factor`, keeping the world position under `screen_pos` fixed
+fn zoom_at(
+    commands: &mut Commands,
+    camera: &Camera,
+    camera_transform: &mut Transform,
+    global_transform: &GlobalTransform,
+    projection: &mut OrthographicProjection,
+    screen_pos: Vec2,
+    factor: f32,
+) {
+    let old_scale = projection.scale;
+    let new_scale = (old_scale * factor).clamp(MIN_ZOOM, MAX_ZOOM);
+    if new_scale == old_scale { return; }
+    let Some(world_pos) = camera.viewport_to_world_2d(global_transform, screen_pos) else { return };
+    commands.remove_resource::<CameraTarget>();
+    projection.scale = new_scale;
+
+    let camera_pos = camera_transform.translation.xy();
+    let new_camera_pos = world_pos - (world_pos - camera_pos) * (new_scale / old_scale);
+    camera_transform.translation.x = new_camera_pos.x;
+    camera_transform.translation.y = new_camera_pos.y;
+}
+
+// Zooms towards the cursor with the scroll wheel, and towards the centre of a two finger pinch.
+// Trackpad pinches arrive as scroll events with ctrl held on most platforms, so they're handled as scrolling.
+fn zoom_camera(
+    mut commands: Commands,
+    mut camera: Query<(&Camera, &mut Transform, &GlobalTransform, &mut OrthographicProjection), With<PrimaryCamera>>,
+    mut scroll: EventReader<MouseWheel>,
+    touches: Res<Touches>,
+    last_cursor_pos: Res<LastPrimaryCursorPos>,
+) {
+    let (camera, mut camera_transform, global_transform, mut projection) = camera.single_mut();
+
+    let lines: f32 = scroll.iter()
+        .map(|ev| match ev.unit {
+            MouseScrollUnit::Line => ev.y,
+            MouseScrollUnit::Pixel => ev.y / PIXELS_PER_LINE,
+        })
+        .sum();
+    if lines != 0.0 {
+        if let Some(cursor_pos) = last_cursor_pos.0 {
+            zoom_at(&mut commands, camera, &mut camera_transform, global_transform, &mut projection, cursor_pos, ZOOM_STEP.powf(-lines));
+        }
+    }
+
+    let fingers: Vec<_> = touches.iter().collect();
+    if let [a, b] = fingers[..] {
+        let distance = a.position().distance(b.position());
+        let previous_distance = a.previous_position().distance(b.previous_position());
+        if distance > 0.0 && previous_distance > 0.0 && distance != previous_distance {
+            let centre = (a.position() + b.position()) / 2.0;
+            zoom_at(&mut commands, camera, &mut camera_transform, global_transform, &mut projection, centre, previous_distance / distance);
+        }
+    }
+}
+
+fn frame_camera_keys(
+    input: Res<Input<KeyCode>>,
+    mut contexts: EguiContexts,
+    mut frame_events: EventWriter<FrameCamera>,
+    mode: Res<State<Mode>>,
+) {
+    // Don't frame while typing into a text field
+    if contexts.ctx_mut().wants_keyboard_input() { return; }
+    if input.just_pressed(KeyCode::Home) {
+        frame_events.send(FrameCamera::All);
+    }
+    // F plays a note on the virtual keyboard in Interact mode
+    if input.just_pressed(KeyCode::F) && mode.0 != Mode::Interact {
+        frame_events.send(FrameCamera::Selection);
+    }
+}
+
+fn frame_camera(
+    mut commands: Commands,
+    mut frame_events: EventReader<FrameCamera>,
+    camera: Query<&Camera, With<PrimaryCamera>>,
+    graph: Res<Graph>,
+    selection: Option<Res<GraphSelection>>,
+    vertices: Query<(Entity, &Transform, &VertexArea), With<Vertex>>,
+) {
+    let Some(frame) = frame_events.iter().last() else { return };
+    let selected: Vec<Entity> = match (frame, selection) {
+        (FrameCamera::Selection, Some(selection)) => selection.vertices()
+            .chain(selection.edges()
+                .filter_map(|e| graph.incident_vertices(&e))
+                .flat_map(|(u, v)| [u, v]))
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let mut min = Vec2::splat(f32::INFINITY);
+    let mut max = Vec2::splat(f32::NEG_INFINITY);
+    for (entity, transform, area) in vertices.iter() {
+        if !selected.is_empty() && !selected.contains(&entity) { continue; }
+        let pos = transform.translation.xy();
+        min = min.min(pos - area.half_extend());
+        max = max.max(pos + area.half_extend());
+    }
+    if min.x > max.x { return; }
+
+    let Some(viewport) = camera.single().logical_viewport_size() else { return };
+    let available = (viewport - 2.0 * FRAME_PADDING).max(Vec2::ONE);
+    let fit = (max - min) / available;
+    commands.insert_resource(CameraTarget {
+        translation: (min + max) / 2.0,
+        scale: fit.max_element().clamp(MIN_ZOOM, MAX_ZOOM),
+    });
+}
+
+fn animate_camera(
+    mut commands: Commands,
+    mut camera: Query<(&mut Transform, &mut OrthographicProjection), With<PrimaryCamera>>,
+    target: Res<CameraTarget>,
+    time: Res<Time>,
+) {
+    let (mut transform, mut projection) = camera.single_mut();
+    let t = (FRAME_SPEED * time.delta_seconds()).min(1.0);
+    let translation = transform.translation.xy().lerp(target.translation, t);
+    let scale = projection.scale + (target.scale - projection.scale) * t;
+
+    if translation.distance(target.translation) < 0.5 && (scale - target.scale).abs() < 0.001 {
+        transform.translation.x = target.translation.x;
+        transform.translation.y = target.translation.y;
+        projection.scale = target.scale;
+        commands.remove_resource::<CameraTarget>();
+    }
+    else {
+        transform.translation.x = translation.x;
+        transform.translation.y = translation.y;
+        projection.scale = scale;
+    }
+}
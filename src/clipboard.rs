@@ -0,0 +1,131 @@
+use bevy::{prelude::*, math::Vec3Swizzles};
+use bevy_egui::EguiContexts;
+
+use crate::{
+    AppSet, Mode,
+    camera::PrimaryCamera,
+    graph::{Graph, GraphSelection, despawn_selection},
+    helper::LastPrimaryCursorPos,
+    node::NodeRegistry,
+    patch::{Patch, PatchVertexQuery},
+};
+
+// Pasted vertices stay on the same grid that dragging snaps to
+const PASTE_GRID: f32 = 20.0;
+const DUPLICATE_OFFSET: Vec2 = Vec2::new(40.0, -40.0);
+
+pub struct ClipboardPlugin;
+
+impl Plugin for ClipboardPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Clipboard>()
+            .add_event::<ClipboardCommand>()
+            .add_systems((
+                clipboard_keys
+                    .run_if(state_exists_and_equals(Mode::Edit)),
+                handle_clipboard,
+            )
+                .chain()
+                .after(AppSet::Ui)
+                .before(AppSet::GraphManagement)
+            );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipboardCommand {
+    Copy,
+    Cut,
+    // Pastes at the cursor
+    Paste,
+    // Pastes a copy of the selection next to it, leaving the clipboard alone
+    Duplicate,
+}
+
+/// The copied vertices and the edges among them.
+#[derive(Resource, Default)]
+pub struct Clipboard(pub Option<Patch>);
+
+fn clipboard_keys(
+    mut contexts: EguiContexts,
+    input: Res<Input<KeyCode>>,
+    mut clipboard_events: EventWriter<ClipboardCommand>,
+) {
+    // Text fields have their own clipboard
+    if contexts.ctx_mut().wants_keyboard_input() { return; }
+    if !input.any_pressed([KeyCode::LControl, KeyCode::RControl, KeyCode::LWin, KeyCode::RWin]) { return; }
+
+    for (key, command) in [
+        (KeyCode::C, ClipboardCommand::Copy),
+        (KeyCode::X, ClipboardCommand::Cut),
+        (KeyCode::V, ClipboardCommand::Paste),
+        (KeyCode::D, ClipboardCommand::Duplicate),
+    ] {
+        if input.just_pressed(key) {
+            clipboard_events.send(command);
+        }
+    }
+}
+
+fn handle_clipboard(
+    mut commands: Commands,
+    mut clipboard_events: EventReader<ClipboardCommand>,
+    mut clipboard: ResMut<Clipboard>,
+    graph: Res<Graph>,
+    registry: Res<NodeRegistry>,
+    selection: Option<Res<GraphSelection>>,
+    last_cursor_pos: Res<LastPrimaryCursorPos>,
+    camera: Query<(&Camera, &GlobalTransform), With<PrimaryCamera>>,
+    vertices: PatchVertexQuery,
+) {
+    for command in clipboard_events.iter() {
+        let copied = || selection.as_ref().map(|selection| Patch::collect_from(&graph, &vertices, selection.vertices()));
+        let (patch, offset) = match command {
+            ClipboardCommand::Copy => {
+                if let Some(patch) = copied() {
+                    clipboard.0 = Some(patch);
+                }
+                continue;
+            }
+            ClipboardCommand::Cut => {
+                let Some(selection) = selection.as_ref() else { continue };
+                clipboard.0 = copied();
+                despawn_selection(&mut commands, &graph, selection);
+                continue;
+            }
+            ClipboardCommand::Paste => {
+                let Some(patch) = clipboard.0.clone() else { continue };
+                let (camera, camera_transform) = camera.single();
+                let target = last_cursor_pos.0
+                    .and_then(|pos| camera.viewport_to_world_2d(camera_transform, pos))
+                    .unwrap_or(camera_transform.translation().xy());
+                let offset = ((target - patch_centre(&patch)) / PASTE_GRID).round() * PASTE_GRID;
+                (patch, offset)
+            }
+            ClipboardCommand::Duplicate => {
+                let Some(patch) = copied() else { continue };
+                (patch, DUPLICATE_OFFSET)
+            }
+        };
+        if patch.vertices.is_empty() { continue; }
+
+        match patch.spawn(&mut commands, &registry, offset) {
+            Ok(entities) => {
+                // Select the copies so they can be dragged straight away
+                let mut new_selection = GraphSelection::default();
+                for entity in entities {
+                    new_selection.insert_vertex(entity);
+                }
+                commands.insert_resource(new_selection);
+            }
+            Err(e) => warn!("Could not paste: {e}"),
+        }
+    }
+}
+
+fn patch_centre(patch: &Patch) -> Vec2 {
+    let positions = patch.vertices.iter().map(|vertex| Vec2::from(vertex.position));
+    let min = positions.clone().fold(Vec2::splat(f32::INFINITY), Vec2::min);
+    let max = positions.fold(Vec2::splat(f32::NEG_INFINITY), Vec2::max);
+    (min + max) / 2.0
+}
@@ -1,11 +1,12 @@
 use std::iter;
 
-use bevy::{prelude::*, math::Vec3Swizzles, utils::{HashMap, HashSet}};
+use bevy::{prelude::*, math::Vec3Swizzles, utils::{HashMap, HashSet}, sprite::Anchor};
+use bevy_egui::EguiContexts;
 use bevy_prototype_lyon::{prelude::{ShapeBundle, GeometryBuilder, Stroke, StrokeOptions, Path, ShapePath}, shapes, plugin::BuildShapes};
 
 pub use bevy_prototype_lyon::prelude::Fill;
 
-use crate::{AppSet, camera::PrimaryCamera, Mode, ui::egui_unfocused, helper::LastPrimaryCursorPos};
+use crate::{AppSet, AudioCommands, AudioRestarted, AudioError, AudioErrorKind, camera::PrimaryCamera, Mode, ui::{egui_unfocused, Notifications}, helper::LastPrimaryCursorPos, node::{NodeKind, NodeParameters, AudioNodes, AudioConnections, AudioConnection, AudioOperation, AudioOperations, port_connection, set_input}};
 
 pub struct GraphPlugin;
 
@@ -23,12 +24,19 @@ impl Plugin for GraphPlugin {
                     .after(AppSet::Ui)
             ))
             .init_resource::<Graph>()
+            .init_resource::<AudioNodes>()
+            .init_resource::<AudioConnections>()
+            .init_resource::<AudioOperations>()
             .add_startup_system(setup.in_set(AppSet::GraphStartup))
             .add_systems((
                 interaction::select,
                 interaction::drag_node
                     .run_if(resource_exists::<GraphSelection>())
+                    .run_if(not(resource_exists::<BoxSelection>()))
                     .run_if(not(state_exists_and_equals(Mode::Interact))),
+                interaction::delete_selection
+                    .run_if(resource_exists::<GraphSelection>())
+                    .run_if(state_exists_and_equals(Mode::Edit)),
                 interaction::create_edge
                     .run_if(resource_exists::<GraphSelection>())
                     .run_if(state_exists_and_equals(Mode::Edit)),
@@ -37,15 +45,32 @@ impl Plugin for GraphPlugin {
                 .chain()
                 .in_set(AppSet::GraphInteraction)
             )
+            // Not gated on egui, so a box released over a panel still finishes
+            .add_system(interaction::box_select
+                .run_if(resource_exists::<BoxSelection>())
+                .after(interaction::select)
+                .before(interaction::drag_node)
+                .in_set(AppSet::GraphInteraction)
+            )
             .add_systems((
+                graph_handle::on_audio_restart,
+                graph_handle::on_node_replaced,
                 graph_handle::on_vertex_change,
+                graph_handle::on_node_kind_change,
+                graph_handle::on_vertex_label_change,
+                graph_handle::on_parameters_change,
                 graph_handle::on_edge_builder,
                 graph_handle::on_vertex_position_change,
                 graph_handle::on_edge_removal,
+                graph_handle::on_audio_error,
             )
                 .chain()
                 .in_set(AppSet::GraphManagement)
-            );
+            )
+            .add_systems((
+                cull_labels,
+                highlight_selection,
+            ).after(AppSet::GraphManagement));
     }
 }
 
@@ -53,6 +78,25 @@ impl Plugin for GraphPlugin {
 pub struct Graph {
     incident_edges: HashMap<Entity, HashSet<Entity>>,
     incident_vertices: HashMap<Entity, (Entity, Entity)>,
+    directed_edges: HashMap<Entity, DirectedEdge>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DirectedEdge {
+    pub source: Entity,
+    pub output: usize,
+    pub sink: Entity,
+    pub input: String,
+}
+
+impl DirectedEdge {
+    pub fn source_port(&self) -> Port {
+        Port::Output(self.output)
+    }
+
+    pub fn sink_port(&self) -> Port {
+        Port::Input(self.input.clone())
+    }
 }
 
 impl Graph {
@@ -85,11 +129,17 @@ impl Graph {
         self.incident_vertices.insert(e, (v1, v2));
     }
 
+    pub fn insert_directed_edge(&mut self, e: Entity, edge: DirectedEdge) {
+        self.insert_edge(e, edge.source, edge.sink);
+        self.directed_edges.insert(e, edge);
+    }
+
     pub fn has_edge(&mut self, e: &Entity) -> bool {
         self.incident_vertices.contains_key(e)
     }
 
     pub fn remove_edge(&mut self, e: &Entity) {
+        self.directed_edges.remove(e);
         let Some((u, v)) = self.incident_vertices.remove(e) else {
             return;
         };
@@ -109,6 +159,47 @@ impl Graph {
         self.incident_vertices.get(&e).copied()
     }
 
+    pub fn directed_edge(&self, e: &Entity) -> Option<&DirectedEdge> {
+        self.directed_edges.get(e)
+    }
+
+    pub fn outgoing(&self, v: &Entity) -> impl Iterator<Item = (Entity, &DirectedEdge)> {
+        let v = *v;
+        self.iter_directed_edges(&v).filter(move |(_, edge)| edge.source == v)
+    }
+
+    pub fn incoming(&self, v: &Entity) -> impl Iterator<Item = (Entity, &DirectedEdge)> {
+        let v = *v;
+        self.iter_directed_edges(&v).filter(move |(_, edge)| edge.sink == v)
+    }
+
+    pub fn edges_into_port<'a>(&'a self, v: &Entity, port: &'a str) -> impl Iterator<Item = (Entity, &'a DirectedEdge)> {
+        self.incoming(v).filter(move |(_, edge)| edge.input == port)
+    }
+
+    pub fn directed_edges(&self) -> impl Iterator<Item = (Entity, &DirectedEdge)> {
+        self.directed_edges.iter().map(|(e, edge)| (*e, edge))
+    }
+
+    fn iter_directed_edges(&self, v: &Entity) -> impl Iterator<Item = (Entity, &DirectedEdge)> {
+        self.incident_edges.get(v)
+            .into_iter()
+            .flatten()
+            .filter_map(|e| self.directed_edges.get(e).map(|edge| (*e, edge)))
+    }
+
+    /// Whether `to` can be reached from `from` by following directed edges.
+    pub fn has_path(&self, from: &Entity, to: &Entity) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = vec![*from];
+        while let Some(v) = stack.pop() {
+            if v == *to { return true; }
+            if !visited.insert(v) { continue; }
+            stack.extend(self.outgoing(&v).map(|(_, edge)| edge.sink));
+        }
+        false
+    }
+
     pub fn get_edge_between(&self, v1: Entity, v2: Entity) -> Option<Entity> {
         let (v1, v2) = (v1.min(v2), v1.max(v2));
         if let Some(in_edges) = self.incident_edges.get(&v1) {
@@ -122,10 +213,115 @@ impl Graph {
     }
 }
 
-#[derive(Resource, Debug, Clone)]
-pub enum GraphSelection {
-    Vertex(Entity),
-    Edge(Entity),
+/// The selected vertices and edges. Removed rather than left empty when nothing is selected.
+#[derive(Resource, Debug, Clone, Default, PartialEq, Eq)]
+pub struct GraphSelection {
+    vertices: HashSet<Entity>,
+    edges: HashSet<Entity>,
+    // The most recently clicked vertex or edge, which single-target tools like edge creation act on
+    primary: Option<Entity>,
+}
+
+impl GraphSelection {
+    pub fn vertex(v: Entity) -> Self {
+        let mut selection = Self::default();
+        selection.insert_vertex(v);
+        selection
+    }
+
+    pub fn edge(e: Entity) -> Self {
+        let mut selection = Self::default();
+        selection.insert_edge(e);
+        selection
+    }
+
+    pub fn insert_vertex(&mut self, v: Entity) {
+        self.vertices.insert(v);
+        self.primary = Some(v);
+    }
+
+    pub fn insert_edge(&mut self, e: Entity) {
+        self.edges.insert(e);
+        self.primary = Some(e);
+    }
+
+    pub fn remove(&mut self, entity: &Entity) {
+        self.vertices.remove(entity);
+        self.edges.remove(entity);
+        if self.primary == Some(*entity) {
+            self.primary = None;
+        }
+    }
+
+    pub fn set_primary(&mut self, entity: Entity) {
+        if self.contains(&entity) {
+            self.primary = Some(entity);
+        }
+    }
+
+    pub fn extend(&mut self, other: &GraphSelection) {
+        self.vertices.extend(other.vertices.iter().copied());
+        self.edges.extend(other.edges.iter().copied());
+    }
+
+    pub fn contains(&self, entity: &Entity) -> bool {
+        self.vertices.contains(entity) || self.edges.contains(entity)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vertices.is_empty() && self.edges.is_empty()
+    }
+
+    pub fn vertices(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.vertices.iter().copied()
+    }
+
+    pub fn edges(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.edges.iter().copied()
+    }
+
+    pub fn num_vertices(&self) -> usize {
+        self.vertices.len()
+    }
+
+    pub fn num_edges(&self) -> usize {
+        self.edges.len()
+    }
+
+    pub fn primary_vertex(&self) -> Option<Entity> {
+        self.primary.filter(|v| self.vertices.contains(v))
+    }
+
+    /// The vertex, if it's the only thing selected.
+    pub fn single_vertex(&self) -> Option<Entity> {
+        match (self.vertices.len(), self.edges.len()) {
+            (1, 0) => self.vertices().next(),
+            _ => None,
+        }
+    }
+
+    /// The edge, if it's the only thing selected.
+    pub fn single_edge(&self) -> Option<Entity> {
+        match (self.vertices.len(), self.edges.len()) {
+            (0, 1) => self.edges().next(),
+            _ => None,
+        }
+    }
+}
+
+/// Despawns everything in the selection and clears it. Edges attached to despawned vertices go with them.
+pub fn despawn_selection(commands: &mut Commands, graph: &Graph, selection: &GraphSelection) {
+    for edge in selection.edges() {
+        let attached = graph.incident_vertices(&edge)
+            .map_or(false, |(u, v)| selection.vertices.contains(&u) || selection.vertices.contains(&v));
+        if !attached {
+            commands.entity(edge).despawn_recursive();
+        }
+    }
+    for vertex in selection.vertices() {
+        commands.entity(vertex).despawn_recursive();
+    }
+    commands.remove_resource::<GraphSelection>();
 }
 
 #[derive(Component, Default, Clone, Debug)]
@@ -134,18 +330,59 @@ pub struct Vertex;
 #[derive(Component, Default, Clone, Debug)]
 pub struct BlankVertex;
 
+pub const VERTEX_HALF_EXTEND: f32 = 20.0;
+
 #[derive(Component, Default, Clone, Debug)]
 pub struct VertexArea {
     half_extend: f32
 }
 
 impl VertexArea {
+    pub fn half_extend(&self) -> f32 {
+        self.half_extend
+    }
+
     pub fn intersects(&self, area_pos: Vec2, other_pos: Vec2) -> bool{
         let diff = (area_pos - other_pos).abs();
         diff.x < self.half_extend && diff.y < self.half_extend
     }
+
+    // Inputs are spread along the left side of the vertex, outputs along the right
+    pub fn port_offset(&self, kind: &NodeKind, port: &Port) -> Option<Vec2> {
+        let (x, index, count) = match port {
+            Port::Input(name) => (-self.half_extend, kind.ports().iter().position(|port| *port == name.as_str())?, kind.ports().len()),
+            Port::Output(index) => (self.half_extend, *index, kind.num_outputs()),
+        };
+        if index >= count { return None; }
+        let spacing = 2.0 * self.half_extend / (count + 1) as f32;
+        Some(Vec2::new(x, self.half_extend - spacing * (index + 1) as f32))
+    }
+}
+
+#[derive(Component, Clone, Debug, PartialEq, Eq)]
+pub enum Port {
+    Input(String),
+    Output(usize),
 }
 
+#[derive(Component)]
+pub struct VertexLabel;
+
+#[derive(Component)]
+pub struct PortLabel;
+
+// Labels are hidden when zoomed out further than this, where they'd be too small to read
+const LABEL_CULL_SCALE: f32 = 2.5;
+
+const SELECTION_COLOUR: Color = Color::rgb(0.2, 0.5, 1.0);
+const SELECTED_VERTEX_COLOUR: Color = Color::rgb(0.75, 0.85, 1.0);
+const ERROR_COLOUR: Color = Color::rgb(1.0, 0.35, 0.3);
+
+const PORT_RADIUS: f32 = 4.0;
+// Hit-testing distances in screen pixels, scaled by the camera zoom into world units
+const PORT_SNAP_DISTANCE: f32 = 15.0;
+const EDGE_SELECT_DISTANCE: f32 = 5.0;
+
 #[derive(Component, Deref, DerefMut)]
 pub struct VertexName(pub String);
 
@@ -191,13 +428,43 @@ pub struct Edge;
 pub struct EdgeBuilder {
     pub u: Entity,
     pub v: Entity,
+    pub output: usize,
+    pub input: String,
 }
 
 
 #[derive(Component)]
 struct DisplayCreationEdge;
 
-fn setup(mut commands: Commands) {
+#[derive(Component)]
+struct DisplaySelectionBox;
+
+// A rubber band selection being dragged out from `start`, in world space
+#[derive(Resource)]
+pub struct BoxSelection {
+    start: Vec2,
+    // Whether the boxed vertices are added to the existing selection
+    additive: bool,
+}
+
+#[derive(Resource)]
+pub struct GraphFont(pub Handle<Font>);
+
+type VertexPorts<'w, 's> = Query<'w, 's, (Entity, &'static Transform, &'static VertexArea, &'static NodeKind), With<Vertex>>;
+
+fn port_position(vertices: &VertexPorts, v: Entity, port: &Port) -> Option<Vec2> {
+    let (_, transform, area, kind) = vertices.get(v).ok()?;
+    Some(transform.translation.xy() + area.port_offset(kind, port)?)
+}
+
+fn edge_endpoints(vertices: &VertexPorts, edge: &DirectedEdge) -> Option<(Vec2, Vec2)> {
+    let from_pos = port_position(vertices, edge.source, &edge.source_port())?;
+    let to_pos = port_position(vertices, edge.sink, &edge.sink_port())?;
+    Some((from_pos, to_pos))
+}
+
+fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(GraphFont(asset_server.load("fonts/DejaVuSans.ttf")));
     commands.spawn((
         DisplayCreationEdge,
         ShapeBundle {
@@ -209,12 +476,76 @@ fn setup(mut commands: Commands) {
             color: Color::BLACK,
         }
     ));
+    commands.spawn((
+        DisplaySelectionBox,
+        ShapeBundle {
+            visibility: Visibility::Hidden,
+            transform: Transform::from_xyz(0.0, 0.0, 5.0),
+            ..default()
+        },
+        Fill::color(SELECTION_COLOUR.with_a(0.15)),
+        Stroke::new(SELECTION_COLOUR, 1.0),
+    ));
+}
+
+fn vertex_label_text(name: &str, kind: Option<&NodeKind>, font: &Handle<Font>) -> Text {
+    let mut sections = vec![TextSection::new(name, TextStyle {
+        font: font.clone(),
+        font_size: 14.0,
+        color: Color::BLACK,
+    })];
+    if let Some(kind) = kind {
+        sections.push(TextSection::new(format!("\n{}", kind.name()), TextStyle {
+            font: font.clone(),
+            font_size: 10.0,
+            color: Color::DARK_GRAY,
+        }));
+    }
+    Text::from_sections(sections).with_alignment(TextAlignment::Center)
+}
+
+fn highlight_selection(
+    selection: Option<Res<GraphSelection>>,
+    notifications: Res<Notifications>,
+    mut vertices: Query<(Entity, &mut Fill), With<Vertex>>,
+    mut edges: Query<(Entity, &mut Stroke), With<Edge>>,
+) {
+    let selected = |entity| selection.as_ref().map_or(false, |selection| selection.contains(&entity));
+    for (entity, mut fill) in vertices.iter_mut() {
+        let colour = if notifications.is_subject(entity) { ERROR_COLOUR }
+            else if selected(entity) { SELECTED_VERTEX_COLOUR }
+            else { Color::WHITE };
+        if fill.color != colour {
+            fill.color = colour;
+        }
+    }
+    for (entity, mut stroke) in edges.iter_mut() {
+        let colour = if notifications.is_subject(entity) { ERROR_COLOUR }
+            else if selected(entity) { SELECTION_COLOUR }
+            else { Color::BLACK };
+        if stroke.color != colour {
+            stroke.color = colour;
+        }
+    }
+}
+
+fn cull_labels(
+    camera: Query<&OrthographicProjection, With<PrimaryCamera>>,
+    mut labels: Query<&mut Visibility, Or<(With<VertexLabel>, With<PortLabel>)>>,
+) {
+    let Ok(projection) = camera.get_single() else { return };
+    let visibility = if projection.scale > LABEL_CULL_SCALE { Visibility::Hidden } else { Visibility::Inherited };
+    for mut label_visibility in labels.iter_mut() {
+        if *label_visibility != visibility {
+            *label_visibility = visibility;
+        }
+    }
 }
 
 mod interaction {
     use super::*;
 
-    pub(super) fn edge_collide(cursor_pos: Vec2, u_pos: Vec2, v_pos: Vec2) -> bool {
+    pub(super) fn edge_collide(cursor_pos: Vec2, u_pos: Vec2, v_pos: Vec2, max_distance: f32) -> bool {
         let m = v_pos - u_pos;
         if m == Vec2::ZERO { return false; }
         let p = cursor_pos - u_pos;
@@ -225,18 +556,21 @@ mod interaction {
             0.0 <= len_squared && len_squared <= m.length_squared()
         };
         let distance_from_edge_squared = (p - proj_m_p).length_squared();
-        cursor_between_vertices && distance_from_edge_squared <= 25.0
+        cursor_between_vertices && distance_from_edge_squared <= max_distance * max_distance
     }
 
     pub(super) fn select(
         mut commands: Commands, 
         graph: Res<Graph>,
         input: Res<Input<MouseButton>>,
+        keys: Res<Input<KeyCode>>,
+        mode: Res<State<Mode>>,
+        selection: Option<Res<GraphSelection>>,
         last_cursor_move: Res<LastPrimaryCursorPos>,
-        camera: Query<(&Camera, &GlobalTransform), With<PrimaryCamera>>,
+        camera: Query<(&Camera, &GlobalTransform, &OrthographicProjection), With<PrimaryCamera>>,
         vertices: Query<(Entity, &GlobalTransform, &VertexArea), With<Vertex>>,
         edges: Query<Entity, With<Edge>>,
-        transforms: Query<&GlobalTransform>,
+        vertex_ports: VertexPorts,
     ) {
         if input.just_pressed(MouseButton::Left) {
             let Some(last_cursor_pos) = last_cursor_move.0 else { 
@@ -244,40 +578,135 @@ mod interaction {
                 return; 
             };
     
-            let (camera, camera_transform) = camera.single();
+            let (camera, camera_transform, projection) = camera.single();
     
             let Some(click_pos) = camera.viewport_to_world_2d(camera_transform, last_cursor_pos) 
             else {
                 commands.remove_resource::<GraphSelection>();
                 return; 
             };
-            
-            for (entity, transform, area) in vertices.iter() {
-                let vertex_pos = transform.translation().xy();
-                if area.intersects(vertex_pos, click_pos) {
-                    commands.insert_resource(GraphSelection::Vertex(entity));
-                    return;
+
+            let clicked_vertex = vertices.iter()
+                .find(|(_, transform, area)| area.intersects(transform.translation().xy(), click_pos))
+                .map(|(entity, ..)| (entity, true));
+            let clicked = clicked_vertex.or_else(|| edges.iter()
+                .find(|entity| {
+                    graph.directed_edge(entity)
+                        .and_then(|edge| edge_endpoints(&vertex_ports, edge))
+                        .map_or(false, |(u_pos, v_pos)| edge_collide(click_pos, u_pos, v_pos, EDGE_SELECT_DISTANCE * projection.scale))
+                })
+                .map(|entity| (entity, false))
+            );
+
+            // Shift adds to or removes from the selection instead of replacing it
+            let additive = keys.any_pressed([KeyCode::LShift, KeyCode::RShift]);
+            let Some((entity, is_vertex)) = clicked else {
+                if !additive {
+                    commands.remove_resource::<GraphSelection>();
+                }
+                if mode.0 != Mode::Interact {
+                    commands.insert_resource(BoxSelection { start: click_pos, additive });
+                }
+                return;
+            };
+
+            let mut new_selection = selection.map_or_else(GraphSelection::default, |selection| selection.clone());
+            if additive && new_selection.contains(&entity) {
+                new_selection.remove(&entity);
+            }
+            else if new_selection.contains(&entity) {
+                // Keep the rest of the selection so that it can be dragged together
+                new_selection.set_primary(entity);
+            }
+            else {
+                if !additive {
+                    new_selection = GraphSelection::default();
+                }
+                if is_vertex {
+                    new_selection.insert_vertex(entity);
+                }
+                else {
+                    new_selection.insert_edge(entity);
                 }
             }
 
-            for entity in edges.iter() {
-                if let Some((u, v)) = graph.incident_vertices(&entity) {
-                    let Ok(u_transform) = transforms.get(u) else { continue };
-                    let Ok(v_transform) = transforms.get(v) else { continue };
-                    let u_pos = u_transform.translation().xy();
-                    let v_pos = v_transform.translation().xy();
+            if new_selection.is_empty() {
+                commands.remove_resource::<GraphSelection>();
+            }
+            else {
+                commands.insert_resource(new_selection);
+            }
+        }
+    }
 
-                    if edge_collide(click_pos, u_pos, v_pos) {
-                        commands.insert_resource(GraphSelection::Edge(entity));
-                        return;
-                    }
-                }
+    pub(super) fn box_select(
+        mut commands: Commands,
+        graph: Res<Graph>,
+        input: Res<Input<MouseButton>>,
+        box_selection: Res<BoxSelection>,
+        selection: Option<Res<GraphSelection>>,
+        last_cursor_pos: Res<LastPrimaryCursorPos>,
+        camera: Query<(&Camera, &GlobalTransform), With<PrimaryCamera>>,
+        vertices: Query<(Entity, &Transform, &VertexArea), With<Vertex>>,
+        mut display_box: Query<(&mut Path, &mut Visibility), With<DisplaySelectionBox>>,
+    ) {
+        let (mut path, mut visibility) = display_box.single_mut();
+        let (camera, camera_transform) = camera.single();
+        let end = last_cursor_pos.0.and_then(|pos| camera.viewport_to_world_2d(camera_transform, pos));
+
+        if input.pressed(MouseButton::Left) {
+            let Some(end) = end else { return };
+            let (min, max) = (box_selection.start.min(end), box_selection.start.max(end));
+            *visibility = Visibility::Visible;
+            *path = ShapePath::build_as(&shapes::Rectangle {
+                extents: max - min,
+                origin: shapes::RectangleOrigin::CustomCenter((min + max) / 2.0),
+            });
+            return;
+        }
+
+        *visibility = Visibility::Hidden;
+        commands.remove_resource::<BoxSelection>();
+        let Some(end) = end else { return };
+        let (min, max) = (box_selection.start.min(end), box_selection.start.max(end));
+
+        let mut new_selection = GraphSelection::default();
+        for (entity, transform, area) in vertices.iter() {
+            let pos = transform.translation.xy();
+            let overlaps = (pos + area.half_extend).cmpge(min).all() && (pos - area.half_extend).cmple(max).all();
+            if overlaps {
+                new_selection.vertices.insert(entity);
             }
-    
-            commands.remove_resource::<GraphSelection>();
+        }
+        for (entity, edge) in graph.directed_edges() {
+            if new_selection.vertices.contains(&edge.source) && new_selection.vertices.contains(&edge.sink) {
+                new_selection.edges.insert(entity);
+            }
+        }
+        if box_selection.additive {
+            if let Some(selection) = selection {
+                new_selection.extend(&selection);
+                new_selection.primary = selection.primary;
+            }
+        }
+
+        if !new_selection.is_empty() {
+            commands.insert_resource(new_selection);
+        }
+    }
+
+    pub(super) fn delete_selection(
+        mut commands: Commands,
+        mut contexts: EguiContexts,
+        graph: Res<Graph>,
+        input: Res<Input<KeyCode>>,
+        selection: Res<GraphSelection>,
+    ) {
+        if contexts.ctx_mut().wants_keyboard_input() { return; }
+        if input.any_just_pressed([KeyCode::Delete, KeyCode::Back]) {
+            despawn_selection(&mut commands, &graph, &selection);
         }
     }
-    
     
     fn quantize(f: f32) -> f32 { (f/20.0).round() * 20.0 }
     fn quantize_vec2(v: Vec2) -> Vec2 { 
@@ -286,8 +715,9 @@ mod interaction {
     }
     
     // TODO: make it so that clicking a node doesn't move it.
+    // The clicked vertex snaps to the grid, and the rest of the selection moves with it
     pub(super) fn drag_node(
-        mut transforms: Query<&mut Transform>,
+        mut transforms: Query<&mut Transform, With<Vertex>>,
         selection: Res<GraphSelection>,
         input: Res<Input<MouseButton>>,
         last_cursor_pos: Res<LastPrimaryCursorPos>,
@@ -299,9 +729,9 @@ mod interaction {
             *cursor_node_diff = None;
         }
         else if input.pressed(MouseButton::Left) {
-            let GraphSelection::Vertex(selected_entity) = *selection 
+            let Some(selected_entity) = selection.primary_vertex() 
                 else { return };
-            let Ok(mut selection_transform) = transforms.get_mut(selected_entity) 
+            let Ok(selection_transform) = transforms.get(selected_entity) 
                 else { return };
             let (camera, camera_transform) = camera.single();
             let Some(last_cursor_pos) = last_cursor_pos.0 else { return };
@@ -315,57 +745,108 @@ mod interaction {
             let Some(cursor_node_diff) = *cursor_node_diff else { return };
             
             let new_transform = world_cursor_pos + cursor_node_diff;
-    
-            selection_transform.translation.x = quantize(new_transform.x);
-            selection_transform.translation.y = quantize(new_transform.y);
+            let delta = Vec2::new(quantize(new_transform.x), quantize(new_transform.y)) - selection_transform.translation.xy();
+            if delta == Vec2::ZERO { return; }
+
+            for vertex in selection.vertices() {
+                let Ok(mut transform) = transforms.get_mut(vertex) else { continue };
+                transform.translation += delta.extend(0.0);
+            }
         }
     }
     
+    fn nearest_output(vertices: &VertexPorts, v: Entity, pos: Vec2) -> Option<usize> {
+        let (_, _, _, kind) = vertices.get(v).ok()?;
+        let distance = |output: &usize| port_position(vertices, v, &Port::Output(*output))
+            .map_or(f32::INFINITY, |port_pos| port_pos.distance(pos));
+        (0..kind.num_outputs()).min_by(|a, b| distance(a).total_cmp(&distance(b)))
+    }
+
+    // The closest input port within snapping distance that doesn't already receive this output
+    fn nearest_input(graph: &Graph, vertices: &VertexPorts, source: Entity, output: usize, pos: Vec2, snap_distance: f32) -> Option<(Entity, String)> {
+        vertices.iter()
+            .filter(|(entity, ..)| *entity != source)
+            .flat_map(|(entity, transform, area, kind)| {
+                kind.ports().iter().filter_map(move |input| {
+                    let port_pos = transform.translation.xy() + area.port_offset(kind, &Port::Input(input.to_string()))?;
+                    Some((entity, *input, port_pos.distance(pos)))
+                })
+            })
+            .filter(|(entity, input, distance)| {
+                *distance <= snap_distance
+                && !graph.outgoing(&source).any(|(_, edge)| edge.sink == *entity && edge.output == output && edge.input == *input)
+            })
+            .min_by(|a, b| a.2.total_cmp(&b.2))
+            .map(|(entity, input, _)| (entity, input.to_string()))
+    }
+
     pub(super) fn create_edge(
         mut commands: Commands,
+        graph: Res<Graph>,
         input: Res<Input<KeyCode>>,
         selection: Res<GraphSelection>,
         last_cursor_pos: Res<LastPrimaryCursorPos>,
         mut display_edge: Query<(&mut Path, &mut Visibility), With<DisplayCreationEdge>>,
-        transforms: Query<&Transform>,
-        camera: Query<(&Camera, &GlobalTransform), With<PrimaryCamera>>,
-        vertices: Query<(Entity, &GlobalTransform, &VertexArea), With<Vertex>>,
+        camera: Query<(&Camera, &GlobalTransform, &OrthographicProjection), With<PrimaryCamera>>,
+        vertices: VertexPorts,
+        mut source_output: Local<Option<usize>>,
     ) {
         if input.pressed(KeyCode::E) {
-            let GraphSelection::Vertex(selected_entity) = *selection else { return };
+            let Some(selected_entity) = selection.primary_vertex() else { return };
             let Some(last_cursor_pos) = last_cursor_pos.0 else { return };
-            let Ok(selected_transform) = transforms.get(selected_entity) else { return };
-            let world_cursor_pos = {
-                let (camera, camera_transform) = camera.single();
-                let Some(pos) = camera.viewport_to_world_2d(camera_transform, last_cursor_pos) else { return; };
-                pos
-            };
+            let (camera, camera_transform, projection) = camera.single();
+            let Some(world_cursor_pos) = camera.viewport_to_world_2d(camera_transform, last_cursor_pos) else { return; };
+            let snap_distance = PORT_SNAP_DISTANCE * projection.scale;
             let (mut path, mut visibility) = display_edge.single_mut();
-            let start_pos = selected_transform.translation.xy();
     
             if input.just_pressed(KeyCode::E) {
-                *visibility = Visibility::Visible;
+                *source_output = nearest_output(&vertices, selected_entity, world_cursor_pos);
+                if source_output.is_some() {
+                    *visibility = Visibility::Visible;
+                }
             }
-            *path = ShapePath::build_as(&shapes::Line(start_pos, world_cursor_pos));
+            let Some(output) = *source_output else { return };
+            let Some(start_pos) = port_position(&vertices, selected_entity, &Port::Output(output)) else { return };
+            let end_pos = nearest_input(&graph, &vertices, selected_entity, output, world_cursor_pos, snap_distance)
+                .and_then(|(entity, input)| port_position(&vertices, entity, &Port::Input(input)))
+                .unwrap_or(world_cursor_pos);
+
+            *path = ShapePath::build_as(&shapes::Line(start_pos, end_pos));
         }
         else if input.just_released(KeyCode::E) {
             let (_, mut visibility) = display_edge.single_mut();
             *visibility = Visibility::Hidden;
     
+            let Some(selected_entity) = selection.primary_vertex() else { return };
+            let Some(output) = source_output.take() else { return };
             let Some(last_cursor_pos) = last_cursor_pos.0 else { return };
-            let world_cursor_pos = {
-                let (camera, camera_transform) = camera.single();
-                let Some(pos) = camera.viewport_to_world_2d(camera_transform, last_cursor_pos) else { return; };
-                pos
-            };
+            let (camera, camera_transform, projection) = camera.single();
+            let Some(world_cursor_pos) = camera.viewport_to_world_2d(camera_transform, last_cursor_pos) else { return; };
+            let snap_distance = PORT_SNAP_DISTANCE * projection.scale;
+
+            if let Some((entity, input)) = nearest_input(&graph, &vertices, selected_entity, output, world_cursor_pos, snap_distance) {
+                commands.spawn(EdgeBuilder {
+                    u: selected_entity,
+                    v: entity,
+                    output,
+                    input,
+                });
+                return;
+            }
     
-            for (entity, trans, area) in vertices.iter() {
-                let GraphSelection::Vertex(selected_entity) = *selection else { return };
-                let vertex_pos = trans.translation().xy();
+            // Released over a vertex but away from its ports: use its first free input
+            for (entity, trans, area, kind) in vertices.iter() {
+                let vertex_pos = trans.translation.xy();
                 if area.intersects(vertex_pos, world_cursor_pos) {
+                    let Some(input) = kind.ports().iter()
+                        .find(|input| graph.edges_into_port(&entity, input).next().is_none())
+                        .or(kind.ports().first())
+                    else { return };
                     commands.spawn(EdgeBuilder {
                         u: selected_entity,
                         v: entity,
+                        output,
+                        input: input.to_string(),
                     });
                     return;
                 }
@@ -377,72 +858,299 @@ mod interaction {
 
 mod graph_handle {
     use super::*;
+    use knyst::graph::Connection;
 
     pub(super) fn on_vertex_change(
         mut commands: Commands,
         mut graph: ResMut<Graph>,
+        mut audio_commands: ResMut<AudioCommands>,
+        mut audio_nodes: ResMut<AudioNodes>,
+        mut audio_operations: ResMut<AudioOperations>,
         mut removed_vertices: RemovedComponents<Vertex>,
-        added_vertices: Query<Entity, Added<Vertex>>,
+        added_vertices: Query<(Entity, Option<&NodeKind>, Option<&NodeParameters>), Added<Vertex>>,
     ) {
-        for entity in added_vertices.iter() {
+        for (entity, kind, parameters) in added_vertices.iter() {
             graph.insert_vertex(entity);
+            if let Some(kind) = kind {
+                let parameters = match parameters {
+                    Some(parameters) => parameters.clone(),
+                    None => {
+                        let parameters = NodeParameters::new(kind);
+                        commands.entity(entity).insert(parameters.clone());
+                        parameters
+                    }
+                };
+                let address = kind.push(&mut audio_commands, &parameters);
+                audio_nodes.insert(entity, address);
+                audio_operations.record(AudioOperation::Push, entity);
+            }
         }
         for entity in removed_vertices.iter() {
             for edge in graph.iter_edges(&entity) {
                 commands.entity(*edge).despawn();
             }
             graph.remove_vertex(&entity);
+            if let Some(address) = audio_nodes.remove(&entity) {
+                audio_commands.free_node(address);
+                audio_operations.record(AudioOperation::Free, entity);
+            }
+        }
+    }
+
+    // Swaps the Knyst node of a vertex whose kind was changed, keeping the edges whose ports still exist
+    pub(super) fn on_node_replaced(
+        mut commands: Commands,
+        graph: Res<Graph>,
+        mut audio_commands: ResMut<AudioCommands>,
+        mut audio_nodes: ResMut<AudioNodes>,
+        mut audio_connections: ResMut<AudioConnections>,
+        mut audio_operations: ResMut<AudioOperations>,
+        mut changed_kinds: Query<(Entity, &NodeKind, &mut NodeParameters), (With<Vertex>, Changed<NodeKind>)>,
+        kinds: Query<&NodeKind>,
+    ) {
+        for (entity, kind, mut parameters) in changed_kinds.iter_mut() {
+            // Vertices added this frame haven't been pushed yet
+            let Some(old_address) = audio_nodes.remove(&entity) else { continue };
+            audio_commands.free_node(old_address);
+            audio_operations.record(AudioOperation::Free, entity);
+
+            if parameters.len() != kind.inputs().len() {
+                *parameters = NodeParameters::new(kind);
+            }
+            let address = kind.push(&mut audio_commands, &parameters);
+            audio_nodes.insert(entity, address);
+            audio_operations.record(AudioOperation::Push, entity);
+
+            for edge in graph.iter_edges(&entity) {
+                audio_connections.remove(edge);
+                let Some(directed_edge) = graph.directed_edge(edge) else { continue };
+                let Some(connection) = audio_connection(directed_edge, &kinds, &audio_nodes) else {
+                    commands.entity(*edge).despawn();
+                    continue;
+                };
+                audio_commands.connect(connection.clone());
+                audio_operations.record(AudioOperation::Connect, *edge);
+                audio_connections.insert(*edge, AudioConnection {
+                    source: directed_edge.source,
+                    sink: directed_edge.sink,
+                    connection,
+                });
+            }
+        }
+    }
+
+    pub(super) fn on_parameters_change(
+        mut audio_commands: ResMut<AudioCommands>,
+        audio_nodes: Res<AudioNodes>,
+        mut audio_operations: ResMut<AudioOperations>,
+        changed_parameters: Query<(Entity, &NodeParameters), Changed<NodeParameters>>,
+    ) {
+        for (entity, parameters) in changed_parameters.iter() {
+            let Some(address) = audio_nodes.get(&entity) else { continue };
+            for (index, value) in parameters.iter().enumerate() {
+                set_input(&mut audio_commands, address, index, *value);
+            }
+            audio_operations.record(AudioOperation::SetInput, entity);
+        }
+    }
+
+    pub(super) fn on_node_kind_change(
+        mut commands: Commands,
+        font: Res<GraphFont>,
+        changed_kinds: Query<(Entity, &NodeKind, &VertexArea, Option<&Children>), (With<Vertex>, Changed<NodeKind>)>,
+        ports: Query<(), With<Port>>,
+    ) {
+        for (entity, kind, area, children) in changed_kinds.iter() {
+            for child in children.into_iter().flatten() {
+                if ports.contains(*child) {
+                    commands.entity(*child).despawn_recursive();
+                }
+            }
+
+            let port_labels = kind.ports().iter()
+                .map(|name| (Port::Input(name.to_string()), *name))
+                .chain(kind.outputs().iter().enumerate().map(|(index, name)| (Port::Output(index), *name)));
+
+            commands.entity(entity).with_children(|parent| {
+                for (port, label) in port_labels {
+                    let Some(offset) = area.port_offset(kind, &port) else { continue };
+                    let (label_x, anchor) = match port {
+                        Port::Input(_) => (PORT_RADIUS + 2.0, Anchor::CenterLeft),
+                        Port::Output(_) => (-PORT_RADIUS - 2.0, Anchor::CenterRight),
+                    };
+                    parent.spawn((
+                        port,
+                        ShapeBundle {
+                            path: GeometryBuilder::build_as(&shapes::Circle { radius: PORT_RADIUS, center: Vec2::ZERO }),
+                            transform: Transform::from_translation(offset.extend(0.1)),
+                            ..default()
+                        },
+                        Fill::color(Color::DARK_GRAY),
+                    )).with_children(|port| {
+                        port.spawn((PortLabel, Text2dBundle {
+                            text: Text::from_section(label, TextStyle {
+                                font: font.0.clone(),
+                                font_size: 10.0,
+                                color: Color::BLACK,
+                            }),
+                            text_anchor: anchor,
+                            transform: Transform::from_xyz(label_x, 0.0, 0.1),
+                            ..default()
+                        }));
+                    });
+                }
+            });
+        }
+    }
 
+    pub(super) fn on_vertex_label_change(
+        mut commands: Commands,
+        font: Res<GraphFont>,
+        changed_vertices: Query<
+            (Entity, &VertexName, &VertexArea, Option<&NodeKind>, Option<&Children>),
+            (With<Vertex>, Or<(Changed<VertexName>, Changed<NodeKind>)>)
+        >,
+        mut labels: Query<&mut Text, With<VertexLabel>>,
+    ) {
+        for (entity, name, area, kind, children) in changed_vertices.iter() {
+            let text = vertex_label_text(name, kind, &font.0);
+            let label = children.into_iter().flatten().find(|child| labels.contains(**child));
+            match label {
+                Some(label) => *labels.get_mut(*label).unwrap() = text,
+                None => {
+                    commands.entity(entity).with_children(|parent| {
+                        parent.spawn((VertexLabel, Text2dBundle {
+                            text,
+                            text_anchor: Anchor::BottomCenter,
+                            transform: Transform::from_xyz(0.0, area.half_extend + 4.0, 0.2),
+                            ..default()
+                        }));
+                    });
+                }
+            }
         }
     }
 
     pub(super) fn on_vertex_position_change(
         graph: Res<Graph>,
-        vertices_with_changed_transforms: Query<Entity, (With<Vertex>, Changed<Transform>)>,
-        transforms: Query<&Transform>,
+        vertices_with_changed_transforms: Query<Entity, (With<Vertex>, Or<(Changed<Transform>, Changed<NodeKind>)>)>,
+        vertex_ports: VertexPorts,
         mut paths: Query<&mut Path>
     ) {
         for vertex in vertices_with_changed_transforms.iter() {
             for edge in graph.iter_edges(&vertex) {
-                let Some((u, v)) = graph.incident_vertices(edge) else {continue };
+                let Some(directed_edge) = graph.directed_edge(edge) else { continue };
                 let Ok(mut path) = paths.get_mut(*edge) else { continue };
-                
-                let (u_pos, v_pos) = {
-                    let Ok(u_transform) = transforms.get(u) else { continue };
-                    let Ok(v_transform) = transforms.get(v) else {continue };
-
-                    (u_transform.translation.xy(), v_transform.translation.xy())
-                };
+                let Some((u_pos, v_pos)) = edge_endpoints(&vertex_ports, directed_edge) else { continue };
 
                 *path = ShapePath::build_as(&shapes::Line(u_pos, v_pos));
             }
         }
     }
 
+    fn audio_connection(edge: &DirectedEdge, kinds: &Query<&NodeKind>, audio_nodes: &AudioNodes) -> Option<Connection> {
+        let source = (kinds.get(edge.source).ok()?, audio_nodes.get(&edge.source)?);
+        let sink = (kinds.get(edge.sink).ok()?, audio_nodes.get(&edge.sink)?);
+        port_connection(source, edge.output, sink, &edge.input)
+    }
+
+    // A restarted audio graph is empty, so every vertex and edge is pushed into it again
+    pub(super) fn on_audio_restart(
+        mut events: EventReader<AudioRestarted>,
+        graph: Res<Graph>,
+        mut audio_commands: ResMut<AudioCommands>,
+        mut audio_nodes: ResMut<AudioNodes>,
+        mut audio_connections: ResMut<AudioConnections>,
+        mut audio_operations: ResMut<AudioOperations>,
+        vertices: Query<(Entity, &NodeKind, &NodeParameters), With<Vertex>>,
+        kinds: Query<&NodeKind>,
+    ) {
+        if events.is_empty() { return; }
+        events.clear();
+
+        audio_nodes.clear();
+        audio_connections.clear();
+        for (entity, kind, parameters) in vertices.iter() {
+            // Vertices not in the graph yet are pushed by `on_vertex_change`
+            if !graph.has_vertex(&entity) { continue; }
+            let address = kind.push(&mut audio_commands, parameters);
+            audio_nodes.insert(entity, address);
+            audio_operations.record(AudioOperation::Push, entity);
+        }
+        for (entity, edge) in graph.directed_edges() {
+            let Some(connection) = audio_connection(edge, &kinds, &audio_nodes) else { continue };
+            audio_commands.connect(connection.clone());
+            audio_operations.record(AudioOperation::Connect, entity);
+            audio_connections.insert(entity, AudioConnection {
+                source: edge.source,
+                sink: edge.sink,
+                connection,
+            });
+        }
+    }
+
     pub(super) fn on_edge_builder(
         mut commands: Commands,
         mut graph: ResMut<Graph>,
-        transforms: Query<&Transform>,
+        mut audio_commands: ResMut<AudioCommands>,
+        audio_nodes: Res<AudioNodes>,
+        mut audio_connections: ResMut<AudioConnections>,
+        mut audio_operations: ResMut<AudioOperations>,
+        mut notifications: ResMut<Notifications>,
+        vertex_ports: VertexPorts,
+        kinds: Query<&NodeKind>,
+        names: Query<&VertexName>,
         added_edge_builders: Query<(Entity, &EdgeBuilder), Added<EdgeBuilder>>,
     ) {
         for (entity, edge_builder) in added_edge_builders.iter() {
             let mut entity_commands = commands.entity(entity);
             
-            if edge_builder.u == edge_builder.v 
-            || !graph.has_vertex(&edge_builder.u) 
+            let directed_edge = DirectedEdge {
+                source: edge_builder.u,
+                output: edge_builder.output,
+                sink: edge_builder.v,
+                input: edge_builder.input.clone(),
+            };
+
+            if !graph.has_vertex(&edge_builder.u) 
             || !graph.has_vertex(&edge_builder.v)
-            || graph.get_edge_between(edge_builder.u, edge_builder.v).is_some() {
+            || graph.outgoing(&edge_builder.u).any(|(_, edge)| *edge == directed_edge) {
                 entity_commands.despawn(); 
                 continue; 
             }
 
+            // Knyst can't schedule a node that depends on its own output
+            if graph.has_path(&edge_builder.v, &edge_builder.u) {
+                let name = |v| names.get(v).map_or("?".to_string(), |name| name.0.clone());
+                notifications.push_about(format!(
+                    "Can't connect {} to {}: it would create a feedback loop",
+                    name(edge_builder.u), name(edge_builder.v)
+                ), Some(edge_builder.v));
+                entity_commands.despawn();
+                continue;
+            }
+
+            let Some(connection) = audio_connection(&directed_edge, &kinds, &audio_nodes) else {
+                entity_commands.despawn();
+                continue;
+            };
+            let Some((from_pos, to_pos)) = edge_endpoints(&vertex_ports, &directed_edge) else {
+                entity_commands.despawn();
+                continue;
+            };
+
             entity_commands.remove::<EdgeBuilder>();
             
-            graph.insert_edge(entity, edge_builder.u, edge_builder.v);
+            graph.insert_directed_edge(entity, directed_edge);
+
+            audio_commands.connect(connection.clone());
+            audio_operations.record(AudioOperation::Connect, entity);
+            audio_connections.insert(entity, AudioConnection {
+                source: edge_builder.u,
+                sink: edge_builder.v,
+                connection,
+            });
 
-            let from_pos = transforms.get(edge_builder.u).unwrap().translation.xy();
-            let to_pos = transforms.get(edge_builder.v).unwrap().translation.xy();
-            
             entity_commands.insert((
                 Edge,
                 ShapeBundle {
@@ -459,10 +1167,104 @@ mod graph_handle {
 
     pub(super) fn on_edge_removal(
         mut removed_edges: RemovedComponents<Edge>,
-        mut graph: ResMut<Graph>
+        mut graph: ResMut<Graph>,
+        mut audio_commands: ResMut<AudioCommands>,
+        audio_nodes: Res<AudioNodes>,
+        mut audio_connections: ResMut<AudioConnections>,
+        mut audio_operations: ResMut<AudioOperations>,
     ) {
         for edge in removed_edges.iter() {
             graph.remove_edge(&edge);
+            let Some(audio_connection) = audio_connections.remove(&edge) else { continue };
+            // Freeing a node already drops its connections
+            if audio_nodes.contains_key(&audio_connection.source) && audio_nodes.contains_key(&audio_connection.sink) {
+                audio_commands.disconnect(audio_connection.connection);
+                audio_operations.record(AudioOperation::Disconnect, edge);
+            }
         }
     }
+
+    pub(super) fn on_audio_error(
+        mut errors: EventReader<AudioError>,
+        mut notifications: ResMut<Notifications>,
+        graph: Res<Graph>,
+        audio_operations: Res<AudioOperations>,
+        names: Query<&VertexName>,
+    ) {
+        for error in errors.iter() {
+            let operations: &[AudioOperation] = match error.kind {
+                AudioErrorKind::Connection => &[AudioOperation::Connect, AudioOperation::Disconnect],
+                AudioErrorKind::Push => &[AudioOperation::Push],
+                AudioErrorKind::Free => &[AudioOperation::Free],
+                AudioErrorKind::Schedule => &[AudioOperation::SetInput],
+                AudioErrorKind::Other => &[],
+            };
+            let subject = audio_operations.latest(operations);
+            let name = |v| names.get(v).map_or("?".to_string(), |name| name.0.clone());
+            let message = match subject {
+                Some(entity) if names.contains(entity) => format!("{}: {}", name(entity), error.message),
+                Some(entity) => match graph.directed_edge(&entity) {
+                    Some(edge) => format!("{} -> {}: {}", name(edge.source), name(edge.sink), error.message),
+                    None => error.message.clone(),
+                },
+                None => error.message.clone(),
+            };
+            notifications.push_about(message, subject);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A graph of `num_vertices` vertices with a directed edge for each (source, sink) pair of indices
+    fn graph(num_vertices: u32, edges: &[(usize, usize)]) -> (Graph, Vec<Entity>) {
+        let mut graph = Graph::default();
+        let vertices: Vec<Entity> = (0..num_vertices).map(Entity::from_raw).collect();
+        for v in &vertices {
+            graph.insert_vertex(*v);
+        }
+        for (i, (source, sink)) in edges.iter().enumerate() {
+            graph.insert_directed_edge(Entity::from_raw(num_vertices + i as u32), DirectedEdge {
+                source: vertices[*source],
+                output: 0,
+                sink: vertices[*sink],
+                input: "in".to_string(),
+            });
+        }
+        (graph, vertices)
+    }
+
+    #[test]
+    fn self_loop_is_a_path() {
+        let (graph, v) = graph(1, &[]);
+        assert!(graph.has_path(&v[0], &v[0]));
+    }
+
+    #[test]
+    fn direct_back_edge() {
+        let (graph, v) = graph(2, &[(0, 1)]);
+        // Connecting 1 to 0 would close a loop, 0 to 1 again wouldn't
+        assert!(graph.has_path(&v[0], &v[1]));
+        assert!(!graph.has_path(&v[1], &v[0]));
+    }
+
+    #[test]
+    fn transitive_cycle() {
+        let (graph, v) = graph(4, &[(0, 1), (1, 2), (2, 3)]);
+        assert!(graph.has_path(&v[0], &v[3]));
+        assert!(graph.has_path(&v[1], &v[3]));
+        assert!(!graph.has_path(&v[3], &v[0]));
+    }
+
+    #[test]
+    fn acyclic_diamond() {
+        let (graph, v) = graph(4, &[(0, 1), (0, 2), (1, 3), (2, 3)]);
+        assert!(graph.has_path(&v[0], &v[3]));
+        // Siblings and the way back up aren't reachable
+        assert!(!graph.has_path(&v[1], &v[2]));
+        assert!(!graph.has_path(&v[2], &v[1]));
+        assert!(!graph.has_path(&v[3], &v[0]));
+    }
 }
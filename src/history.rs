@@ -0,0 +1,496 @@
+use std::{collections::VecDeque, mem};
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_egui::EguiContexts;
+
+use crate::{
+    AppSet,
+    graph::{Graph, GraphSelection, DirectedEdge, Edge, EdgeBuilder, Vertex, VertexArea, VertexBundle, VertexName},
+    node::{NodeKind, NodeParameters},
+};
+
+/// The number of undo steps kept.
+pub const HISTORY_SIZE: usize = 100;
+
+pub struct HistoryPlugin;
+
+impl Plugin for HistoryPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<History>()
+            .add_event::<Undo>()
+            .add_event::<Redo>()
+            .add_systems((
+                undo_redo_keys,
+                apply_history,
+            )
+                .chain()
+                .after(AppSet::Ui)
+                .before(AppSet::GraphManagement)
+            )
+            .add_system(record_changes.after(AppSet::GraphManagement));
+    }
+}
+
+pub struct Undo;
+
+pub struct Redo;
+
+#[derive(Debug, Clone, PartialEq)]
+struct VertexState {
+    name: String,
+    kind: Option<NodeKind>,
+    parameters: NodeParameters,
+    position: Vec3,
+    half_extend: f32,
+}
+
+#[derive(Debug, Clone)]
+enum EditCommand {
+    CreateVertex(Entity, VertexState),
+    DeleteVertex(Entity, VertexState),
+    // Covers moving, renaming, changing the node kind and editing parameters
+    ModifyVertex { vertex: Entity, from: VertexState, to: VertexState },
+    CreateEdge(Entity, DirectedEdge),
+    DeleteEdge(Entity, DirectedEdge),
+}
+
+impl EditCommand {
+    fn inverse(&self) -> Self {
+        match self {
+            EditCommand::CreateVertex(v, state) => EditCommand::DeleteVertex(*v, state.clone()),
+            EditCommand::DeleteVertex(v, state) => EditCommand::CreateVertex(*v, state.clone()),
+            EditCommand::ModifyVertex { vertex, from, to } => EditCommand::ModifyVertex { vertex: *vertex, from: to.clone(), to: from.clone() },
+            EditCommand::CreateEdge(e, edge) => EditCommand::DeleteEdge(*e, edge.clone()),
+            EditCommand::DeleteEdge(e, edge) => EditCommand::CreateEdge(*e, edge.clone()),
+        }
+    }
+
+    // The vertex, if this only changes its name
+    fn renames(&self) -> Option<Entity> {
+        let EditCommand::ModifyVertex { vertex, from, to } = self else { return None };
+        let renamed = VertexState { name: to.name.clone(), ..from.clone() };
+        (renamed == *to).then_some(*vertex)
+    }
+}
+
+type Step = Vec<EditCommand>;
+
+/// Records edits to the graph as they happen, so that they can be undone and redone.
+///
+/// Edits are picked up from component changes rather than recorded by each editing system,
+/// and changes made over consecutive frames (like dragging) are coalesced into a single step.
+#[derive(Resource, Default)]
+pub struct History {
+    undo: VecDeque<Step>,
+    redo: Vec<Step>,
+    // Changes not yet committed as a step
+    pending: Step,
+    // The last known state of every vertex and edge
+    vertices: HashMap<Entity, VertexState>,
+    edges: HashMap<Entity, DirectedEdge>,
+    // Entities re-spawned by undo or redo, from the entity recorded in the history to its replacement
+    remap: HashMap<Entity, Entity>,
+    // The vertex renamed by the last step, which further renames are merged into
+    last_rename: Option<Entity>,
+}
+
+impl History {
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty() || !self.pending.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    fn resolve(&self, mut entity: Entity) -> Entity {
+        while let Some(next) = self.remap.get(&entity) {
+            entity = *next;
+        }
+        entity
+    }
+
+    fn record(&mut self, command: EditCommand) {
+        let pending = &mut self.pending;
+        match command {
+            EditCommand::ModifyVertex { vertex, from, to } => {
+                for recorded in pending.iter_mut() {
+                    match recorded {
+                        EditCommand::CreateVertex(v, state) if *v == vertex => {
+                            *state = to;
+                            return;
+                        }
+                        EditCommand::ModifyVertex { vertex: v, to: recorded_to, .. } if *v == vertex => {
+                            *recorded_to = to;
+                            return;
+                        }
+                        _ => {}
+                    }
+                }
+                pending.push(EditCommand::ModifyVertex { vertex, from, to });
+            }
+            EditCommand::DeleteVertex(vertex, state) => {
+                let earlier = pending.iter().position(|recorded| matches!(recorded,
+                    EditCommand::CreateVertex(v, _) | EditCommand::ModifyVertex { vertex: v, .. } if *v == vertex
+                ));
+                match earlier.map(|index| pending.remove(index)) {
+                    // Created and deleted before being committed, so there's nothing to undo
+                    Some(EditCommand::CreateVertex(..)) => {}
+                    Some(EditCommand::ModifyVertex { from, .. }) => pending.push(EditCommand::DeleteVertex(vertex, from)),
+                    _ => pending.push(EditCommand::DeleteVertex(vertex, state)),
+                }
+            }
+            EditCommand::DeleteEdge(edge, directed_edge) => {
+                let created = pending.iter().position(|recorded| matches!(recorded, EditCommand::CreateEdge(e, _) if *e == edge));
+                match created {
+                    Some(index) => { pending.remove(index); }
+                    None => pending.push(EditCommand::DeleteEdge(edge, directed_edge)),
+                }
+            }
+            command => pending.push(command),
+        }
+    }
+
+    fn commit(&mut self) {
+        if self.pending.is_empty() { return; }
+        let step = mem::take(&mut self.pending);
+        self.redo.clear();
+
+        // Typing a name changes it every keystroke, which should be undone all at once
+        let renamed = match &step[..] {
+            [command] => command.renames(),
+            _ => None,
+        };
+        if renamed.is_some() && renamed == self.last_rename {
+            if let (Some([EditCommand::ModifyVertex { to, .. }]), EditCommand::ModifyVertex { to: new_to, .. }) =
+                (self.undo.back_mut().map(|last| &mut last[..]), &step[0])
+            {
+                *to = new_to.clone();
+                return;
+            }
+        }
+        self.last_rename = renamed;
+
+        self.undo.push_back(step);
+        if self.undo.len() > HISTORY_SIZE {
+            self.undo.pop_front();
+        }
+    }
+
+    fn apply(&mut self, commands: &mut Commands, step: &[EditCommand]) {
+        self.last_rename = None;
+        let deleted_vertices: Vec<Entity> = step.iter()
+            .filter_map(|command| match command {
+                EditCommand::DeleteVertex(v, _) => Some(self.resolve(*v)),
+                _ => None,
+            })
+            .collect();
+
+        // Vertices have to exist before edges between them are built, and edges of deleted vertices go with them
+        for command in step {
+            let EditCommand::DeleteEdge(e, _) = command else { continue };
+            let e = self.resolve(*e);
+            if let Some(edge) = self.edges.remove(&e) {
+                if deleted_vertices.contains(&edge.source) || deleted_vertices.contains(&edge.sink) { continue; }
+            }
+            if let Some(entity_commands) = commands.get_entity(e) {
+                entity_commands.despawn_recursive();
+            }
+        }
+        for v in deleted_vertices.iter() {
+            self.vertices.remove(v);
+            self.edges.retain(|_, edge| edge.source != *v && edge.sink != *v);
+            if let Some(entity_commands) = commands.get_entity(*v) {
+                entity_commands.despawn_recursive();
+            }
+        }
+        for command in step {
+            let EditCommand::CreateVertex(v, state) = command else { continue };
+            let mut entity_commands = commands.spawn(VertexBundle::new(state.position, state.name.clone(), state.half_extend));
+            if let Some(kind) = &state.kind {
+                entity_commands.insert((kind.clone(), state.parameters.clone()));
+            }
+            let entity = entity_commands.id();
+            self.remap.insert(*v, entity);
+            self.vertices.insert(entity, state.clone());
+        }
+        for command in step {
+            let EditCommand::ModifyVertex { vertex, from, to } = command else { continue };
+            let v = self.resolve(*vertex);
+            let Some(mut entity_commands) = commands.get_entity(v) else { continue };
+            entity_commands.insert((Transform::from_translation(to.position), VertexName(to.name.clone())));
+            // Re-inserting the same kind would needlessly replace its audio node
+            if let Some(kind) = to.kind.as_ref().filter(|kind| from.kind.as_ref() != Some(kind)) {
+                entity_commands.insert(kind.clone());
+            }
+            if to.parameters != from.parameters {
+                entity_commands.insert(to.parameters.clone());
+            }
+            self.vertices.insert(v, to.clone());
+        }
+        for command in step {
+            let EditCommand::CreateEdge(e, edge) = command else { continue };
+            let edge = DirectedEdge {
+                source: self.resolve(edge.source),
+                sink: self.resolve(edge.sink),
+                ..edge.clone()
+            };
+            let entity = commands.spawn(EdgeBuilder {
+                u: edge.source,
+                v: edge.sink,
+                output: edge.output,
+                input: edge.input.clone(),
+            }).id();
+            self.remap.insert(*e, entity);
+            self.edges.insert(entity, edge);
+        }
+    }
+}
+
+fn undo_redo_keys(
+    mut contexts: EguiContexts,
+    input: Res<Input<KeyCode>>,
+    mut undo_events: EventWriter<Undo>,
+    mut redo_events: EventWriter<Redo>,
+) {
+    // Text fields have their own undo
+    if contexts.ctx_mut().wants_keyboard_input() { return; }
+    let ctrl = input.any_pressed([KeyCode::LControl, KeyCode::RControl, KeyCode::LWin, KeyCode::RWin]);
+    let shift = input.any_pressed([KeyCode::LShift, KeyCode::RShift]);
+    if !ctrl { return; }
+
+    if input.just_pressed(KeyCode::Z) {
+        if shift {
+            redo_events.send(Redo);
+        }
+        else {
+            undo_events.send(Undo);
+        }
+    }
+    if input.just_pressed(KeyCode::Y) {
+        redo_events.send(Redo);
+    }
+}
+
+fn apply_history(
+    mut commands: Commands,
+    mut history: ResMut<History>,
+    mut undo_events: EventReader<Undo>,
+    mut redo_events: EventReader<Redo>,
+) {
+    let undos = undo_events.iter().count();
+    let redos = redo_events.iter().count();
+    if undos == 0 && redos == 0 { return; }
+    history.commit();
+
+    for _ in 0..undos {
+        let Some(step) = history.undo.pop_back() else { break };
+        let inverse: Step = step.iter().map(EditCommand::inverse).collect();
+        history.apply(&mut commands, &inverse);
+        history.redo.push(step);
+    }
+    for _ in 0..redos {
+        let Some(step) = history.redo.pop() else { break };
+        history.apply(&mut commands, &step);
+        history.undo.push_back(step);
+    }
+    // The selection may refer to vertices that no longer exist
+    commands.remove_resource::<GraphSelection>();
+}
+
+fn record_changes(
+    mut history: ResMut<History>,
+    graph: Res<Graph>,
+    mouse: Res<Input<MouseButton>>,
+    changed_vertices: Query<
+        (Entity, &VertexName, &Transform, &VertexArea, Option<&NodeKind>, Option<&NodeParameters>),
+        (With<Vertex>, Or<(Changed<Transform>, Changed<VertexName>, Changed<NodeKind>, Changed<NodeParameters>)>)
+    >,
+    added_edges: Query<Entity, Added<Edge>>,
+    mut removed_vertices: RemovedComponents<Vertex>,
+    mut removed_edges: RemovedComponents<Edge>,
+) {
+    let history = &mut *history;
+    let mut changed = false;
+
+    for (entity, name, transform, area, kind, parameters) in changed_vertices.iter() {
+        let state = VertexState {
+            name: name.0.clone(),
+            kind: kind.cloned(),
+            // Missing parameters are filled in with the defaults when the node is pushed
+            parameters: parameters.cloned()
+                .or_else(|| kind.map(NodeParameters::new))
+                .unwrap_or(NodeParameters(Vec::new())),
+            position: transform.translation,
+            half_extend: area.half_extend(),
+        };
+        match history.vertices.insert(entity, state.clone()) {
+            Some(old_state) if old_state == state => {}
+            Some(old_state) => {
+                history.record(EditCommand::ModifyVertex { vertex: entity, from: old_state, to: state });
+                changed = true;
+            }
+            None => {
+                history.record(EditCommand::CreateVertex(entity, state));
+                changed = true;
+            }
+        }
+    }
+    for entity in removed_vertices.iter() {
+        let Some(state) = history.vertices.remove(&entity) else { continue };
+        // Its edges are despawned in a later frame, but belong to the same step
+        let attached: Vec<Entity> = history.edges.iter()
+            .filter(|(_, edge)| edge.source == entity || edge.sink == entity)
+            .map(|(e, _)| *e)
+            .collect();
+        for e in attached {
+            if let Some(edge) = history.edges.remove(&e) {
+                history.record(EditCommand::DeleteEdge(e, edge));
+            }
+        }
+        history.record(EditCommand::DeleteVertex(entity, state));
+        changed = true;
+    }
+
+    for entity in added_edges.iter() {
+        if history.edges.contains_key(&entity) { continue; }
+        let Some(edge) = graph.directed_edge(&entity) else { continue };
+        history.edges.insert(entity, edge.clone());
+        history.record(EditCommand::CreateEdge(entity, edge.clone()));
+        changed = true;
+    }
+    for entity in removed_edges.iter() {
+        let Some(edge) = history.edges.remove(&entity) else { continue };
+        history.record(EditCommand::DeleteEdge(entity, edge));
+        changed = true;
+    }
+
+    // Wait for a quiet frame, with nothing being dragged, before committing
+    if !changed && !mouse.any_pressed([MouseButton::Left, MouseButton::Middle, MouseButton::Right]) {
+        history.commit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(name: &str, x: f32) -> VertexState {
+        VertexState {
+            name: name.to_string(),
+            kind: None,
+            parameters: NodeParameters(Vec::new()),
+            position: Vec3::new(x, 0.0, 0.0),
+            half_extend: 20.0,
+        }
+    }
+
+    fn modify(vertex: Entity, from: VertexState, to: VertexState) -> EditCommand {
+        EditCommand::ModifyVertex { vertex, from, to }
+    }
+
+    fn edge(n: u32) -> EditCommand {
+        EditCommand::CreateEdge(Entity::from_raw(n), DirectedEdge {
+            source: Entity::from_raw(1000),
+            output: 0,
+            sink: Entity::from_raw(1001),
+            input: "in".to_string(),
+        })
+    }
+
+    fn created_edge(step: &Step) -> Option<u32> {
+        match &step[..] {
+            [EditCommand::CreateEdge(e, _)] => Some(e.index()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn modify_after_create_folds_into_create() {
+        let v = Entity::from_raw(0);
+        let mut history = History::default();
+        history.record(EditCommand::CreateVertex(v, state("a", 0.0)));
+        history.record(modify(v, state("a", 0.0), state("a", 5.0)));
+        assert!(matches!(&history.pending[..], [EditCommand::CreateVertex(created, to)] if *created == v && *to == state("a", 5.0)));
+    }
+
+    #[test]
+    fn repeated_modifies_merge() {
+        let v = Entity::from_raw(0);
+        let mut history = History::default();
+        history.record(modify(v, state("a", 0.0), state("a", 5.0)));
+        history.record(modify(v, state("a", 5.0), state("a", 10.0)));
+        history.commit();
+        assert_eq!(history.undo.len(), 1);
+        assert!(matches!(&history.undo[0][..], [EditCommand::ModifyVertex { from, to, .. }]
+            if *from == state("a", 0.0) && *to == state("a", 10.0)));
+    }
+
+    #[test]
+    fn renames_merge_across_steps() {
+        let v = Entity::from_raw(0);
+        let mut history = History::default();
+        for (from, to) in [("", "o"), ("o", "os"), ("os", "osc")] {
+            history.record(modify(v, state(from, 0.0), state(to, 0.0)));
+            history.commit();
+        }
+        assert_eq!(history.undo.len(), 1);
+        assert!(matches!(&history.undo[0][..], [EditCommand::ModifyVertex { from, to, .. }]
+            if from.name.is_empty() && to.name == "osc"));
+
+        // Anything else in between starts a new rename
+        history.record(modify(v, state("osc", 0.0), state("osc", 5.0)));
+        history.commit();
+        history.record(modify(v, state("osc", 5.0), state("osc1", 5.0)));
+        history.commit();
+        assert_eq!(history.undo.len(), 3);
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut history = History::default();
+        for n in 0..HISTORY_SIZE as u32 + 50 {
+            history.record(edge(n));
+            history.commit();
+        }
+        assert_eq!(history.undo.len(), HISTORY_SIZE);
+        // The oldest steps are dropped
+        assert_eq!(history.undo.front().and_then(created_edge), Some(50));
+        assert_eq!(history.undo.back().and_then(created_edge), Some(HISTORY_SIZE as u32 + 49));
+    }
+
+    #[test]
+    fn undo_and_redo_in_order() {
+        let mut app = App::new();
+        app.init_resource::<History>()
+            .add_event::<Undo>()
+            .add_event::<Redo>()
+            .add_system(apply_history);
+
+        for n in 0..3 {
+            let mut history = app.world.resource_mut::<History>();
+            history.record(edge(n));
+            history.commit();
+        }
+
+        app.world.send_event(Undo);
+        app.world.send_event(Undo);
+        app.update();
+        let history = app.world.resource::<History>();
+        assert_eq!(history.undo.iter().filter_map(created_edge).collect::<Vec<_>>(), [0]);
+        assert_eq!(history.redo.iter().filter_map(created_edge).collect::<Vec<_>>(), [2, 1]);
+
+        app.world.send_event(Redo);
+        app.update();
+        let history = app.world.resource::<History>();
+        assert_eq!(history.undo.iter().filter_map(created_edge).collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(history.redo.iter().filter_map(created_edge).collect::<Vec<_>>(), [2]);
+
+        // A new edit can't be redone past
+        let mut history = app.world.resource_mut::<History>();
+        history.record(edge(3));
+        history.commit();
+        assert!(!history.can_redo());
+        assert_eq!(history.undo.iter().filter_map(created_edge).collect::<Vec<_>>(), [0, 1, 3]);
+    }
+}
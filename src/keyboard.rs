@@ -0,0 +1,287 @@
+use bevy::{prelude::*, utils::HashMap};
+use bevy_egui::{EguiContexts, egui::{self, Id}};
+use knyst::{prelude::*, graph::NodeAddress, controller::KnystCommands};
+
+use crate::{
+    AppSet, AudioCommands, Mode,
+    node::{NodeKind, NodeRegistry, NodeType, RegisterNodeType, AudioNodes, AudioOperation, AudioOperations, set_input},
+    perform::performance_panel,
+};
+
+const PIANO_PANEL_ID: &str = "piano_panel";
+
+// Semitones above the keyboard's lowest C, laid out like a piano's keys
+const NOTE_KEYS: [(KeyCode, u8); 17] = [
+    (KeyCode::A, 0), (KeyCode::W, 1), (KeyCode::S, 2), (KeyCode::E, 3), (KeyCode::D, 4),
+    (KeyCode::F, 5), (KeyCode::T, 6), (KeyCode::G, 7), (KeyCode::Y, 8), (KeyCode::H, 9),
+    (KeyCode::U, 10), (KeyCode::J, 11), (KeyCode::K, 12), (KeyCode::O, 13), (KeyCode::L, 14),
+    (KeyCode::P, 15), (KeyCode::Semicolon, 16),
+];
+const MAX_OCTAVE: i32 = 8;
+const VELOCITY_STEP: f32 = 0.125;
+const MIN_VELOCITY: f32 = 0.05;
+
+const PIANO_OCTAVES: u8 = 2;
+const WHITE_KEY_SIZE: egui::Vec2 = egui::vec2(24.0, 90.0);
+const BLACK_KEY_SIZE: egui::Vec2 = egui::vec2(16.0, 55.0);
+// Semitones of the white keys in an octave
+const WHITE_KEYS: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+const HELD_KEY_COLOUR: egui::Color32 = egui::Color32::from_rgb(90, 170, 255);
+
+pub struct KeyboardPlugin;
+
+impl Plugin for KeyboardPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_node_types(register_nodes)
+            .init_resource::<VirtualKeyboard>()
+            .add_event::<NoteEvent>()
+            .add_system(piano_panel
+                .in_set(AppSet::Ui)
+                .after(performance_panel)
+                .run_if(state_exists_and_equals(Mode::Interact))
+            )
+            .add_systems((
+                computer_keys
+                    .run_if(state_exists_and_equals(Mode::Interact)),
+                play_notes,
+            )
+                .chain()
+                .after(AppSet::Ui)
+                .before(AppSet::GraphManagement)
+            )
+            .add_system(release_notes.in_schedule(OnExit(Mode::Interact)));
+    }
+}
+
+pub fn register_nodes(registry: &mut NodeRegistry) {
+    registry.register(KeyboardNode);
+}
+
+/// Outputs the frequency, gate and velocity of the note played on the virtual keyboard.
+pub struct KeyboardNode;
+
+impl NodeType for KeyboardNode {
+    fn name(&self) -> &'static str { "Keyboard" }
+
+    fn category(&self) -> &'static str { "Sources" }
+
+    fn inputs(&self) -> &'static [&'static str] { &[] }
+
+    fn outputs(&self) -> &'static [&'static str] { &["freq", "gate", "velocity"] }
+
+    fn default_inputs(&self) -> &'static [f32] { &[] }
+
+    // Passes hidden inputs through, which the virtual keyboard sets
+    fn construct(&self, commands: &mut KnystCommands) -> NodeAddress {
+        commands.push(Bus(3), inputs!())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoteEvent {
+    /// `velocity` is between 0 and 1.
+    On { note: u8, velocity: f32 },
+    Off { note: u8 },
+}
+
+pub fn note_frequency(note: u8) -> f32 {
+    440.0 * 2.0_f32.powf((note as f32 - 69.0) / 12.0)
+}
+
+/// The notes held on the computer keyboard and the on-screen piano.
+///
+/// Keyboard nodes are monophonic and follow the most recently pressed note that's still held.
+#[derive(Resource)]
+pub struct VirtualKeyboard {
+    /// The octave of the lowest C, where middle C is in octave 4.
+    pub octave: i32,
+    pub velocity: f32,
+    held: Vec<(u8, f32)>,
+    // The note each held key started, so shifting octaves doesn't leave notes hanging
+    keys: HashMap<KeyCode, u8>,
+    pointer_note: Option<u8>,
+}
+
+impl Default for VirtualKeyboard {
+    fn default() -> Self {
+        VirtualKeyboard {
+            octave: 4,
+            velocity: 0.75,
+            held: Vec::new(),
+            keys: HashMap::default(),
+            pointer_note: None,
+        }
+    }
+}
+
+impl VirtualKeyboard {
+    fn base_note(&self) -> u8 {
+        (12 * (self.octave + 1)) as u8
+    }
+
+    pub fn is_held(&self, note: u8) -> bool {
+        self.held.iter().any(|(held, _)| *held == note)
+    }
+}
+
+fn computer_keys(
+    mut contexts: EguiContexts,
+    input: Res<Input<KeyCode>>,
+    mut keyboard: ResMut<VirtualKeyboard>,
+    mut note_events: EventWriter<NoteEvent>,
+) {
+    for (key, _) in NOTE_KEYS {
+        if input.just_released(key) {
+            if let Some(note) = keyboard.keys.remove(&key) {
+                note_events.send(NoteEvent::Off { note });
+            }
+        }
+    }
+
+    // Leave text fields and shortcuts like Ctrl+Z alone
+    if contexts.ctx_mut().wants_keyboard_input() { return; }
+    if input.any_pressed([KeyCode::LControl, KeyCode::RControl, KeyCode::LWin, KeyCode::RWin]) { return; }
+
+    if input.just_pressed(KeyCode::Z) {
+        keyboard.octave = (keyboard.octave - 1).max(0);
+    }
+    if input.just_pressed(KeyCode::X) {
+        keyboard.octave = (keyboard.octave + 1).min(MAX_OCTAVE);
+    }
+    if input.just_pressed(KeyCode::C) {
+        keyboard.velocity = (keyboard.velocity - VELOCITY_STEP).max(MIN_VELOCITY);
+    }
+    if input.just_pressed(KeyCode::V) {
+        keyboard.velocity = (keyboard.velocity + VELOCITY_STEP).min(1.0);
+    }
+
+    for (key, semitones) in NOTE_KEYS {
+        if !input.just_pressed(key) { continue; }
+        let Some(note) = keyboard.base_note().checked_add(semitones).filter(|note| *note < 128) else { continue };
+        keyboard.keys.insert(key, note);
+        note_events.send(NoteEvent::On { note, velocity: keyboard.velocity });
+    }
+}
+
+// Returns the note under the pointer while it's pressed
+fn piano(ui: &mut egui::Ui, first_note: u8, keyboard: &VirtualKeyboard) -> Option<u8> {
+    let num_white_keys = 7 * PIANO_OCTAVES as usize + 1;
+    let size = egui::vec2(num_white_keys as f32 * WHITE_KEY_SIZE.x, WHITE_KEY_SIZE.y);
+    let (rect, response) = ui.allocate_exact_size(size, egui::Sense::click_and_drag());
+
+    let white_keys = (0..num_white_keys).map(|i| {
+        let note = first_note + 12 * (i / 7) as u8 + WHITE_KEYS[i % 7];
+        let min = rect.left_top() + egui::vec2(i as f32 * WHITE_KEY_SIZE.x, 0.0);
+        (note, egui::Rect::from_min_size(min, WHITE_KEY_SIZE))
+    });
+    // A black key sits above each boundary between white keys a whole tone apart
+    let black_keys = (0..num_white_keys - 1)
+        .filter(|i| ![2, 6].contains(&(i % 7)))
+        .map(|i| {
+            let note = first_note + 12 * (i / 7) as u8 + WHITE_KEYS[i % 7] + 1;
+            let centre_x = rect.left() + (i + 1) as f32 * WHITE_KEY_SIZE.x;
+            let min = egui::pos2(centre_x - BLACK_KEY_SIZE.x / 2.0, rect.top());
+            (note, egui::Rect::from_min_size(min, BLACK_KEY_SIZE))
+        });
+
+    let stroke = ui.visuals().widgets.noninteractive.fg_stroke;
+    let painter = ui.painter();
+    for (note, key_rect) in white_keys.clone() {
+        let fill = if keyboard.is_held(note) { HELD_KEY_COLOUR } else { egui::Color32::WHITE };
+        painter.rect(key_rect, 2.0, fill, stroke);
+    }
+    for (note, key_rect) in black_keys.clone() {
+        let fill = if keyboard.is_held(note) { HELD_KEY_COLOUR } else { egui::Color32::BLACK };
+        painter.rect(key_rect, 2.0, fill, stroke);
+    }
+
+    if !response.is_pointer_button_down_on() { return None; }
+    let pos = response.interact_pointer_pos()?;
+    // Black keys are drawn on top, so they win
+    black_keys.chain(white_keys)
+        .find(|(_, key_rect)| key_rect.contains(pos))
+        .map(|(note, _)| note)
+}
+
+fn piano_panel(
+    mut contexts: EguiContexts,
+    mut keyboard: ResMut<VirtualKeyboard>,
+    mut note_events: EventWriter<NoteEvent>,
+) {
+    egui::TopBottomPanel::bottom(Id::new(PIANO_PANEL_ID)).show(contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            ui.label("Octave (Z/X)");
+            if ui.small_button("-").clicked() {
+                keyboard.octave = (keyboard.octave - 1).max(0);
+            }
+            ui.label(keyboard.octave.to_string());
+            if ui.small_button("+").clicked() {
+                keyboard.octave = (keyboard.octave + 1).min(MAX_OCTAVE);
+            }
+            ui.separator();
+            ui.add(egui::Slider::new(&mut keyboard.velocity, MIN_VELOCITY..=1.0).text("Velocity (C/V)"));
+        });
+
+        // The top octaves don't fit a whole piano
+        let highest_c = (127 - 12 * PIANO_OCTAVES) / 12 * 12;
+        let first_note = keyboard.base_note().min(highest_c);
+        let note = piano(ui, first_note, &keyboard);
+        if note != keyboard.pointer_note {
+            if let Some(note) = keyboard.pointer_note {
+                note_events.send(NoteEvent::Off { note });
+            }
+            if let Some(note) = note {
+                note_events.send(NoteEvent::On { note, velocity: keyboard.velocity });
+            }
+            keyboard.pointer_note = note;
+        }
+    });
+}
+
+fn play_notes(
+    mut note_events: EventReader<NoteEvent>,
+    mut keyboard: ResMut<VirtualKeyboard>,
+    audio_commands: Option<ResMut<AudioCommands>>,
+    audio_nodes: Res<AudioNodes>,
+    mut audio_operations: ResMut<AudioOperations>,
+    vertices: Query<(Entity, &NodeKind)>,
+) {
+    if note_events.is_empty() { return; }
+    for event in note_events.iter() {
+        match *event {
+            NoteEvent::On { note, velocity } => {
+                keyboard.held.retain(|(held, _)| *held != note);
+                keyboard.held.push((note, velocity));
+            }
+            NoteEvent::Off { note } => keyboard.held.retain(|(held, _)| *held != note),
+        }
+    }
+
+    let Some(mut audio_commands) = audio_commands else { return };
+    let playing = keyboard.held.last().copied();
+
+    for (entity, kind) in vertices.iter() {
+        if kind.name() != KeyboardNode.name() { continue; }
+        let Some(address) = audio_nodes.get(&entity) else { continue };
+        match playing {
+            Some((note, velocity)) => {
+                set_input(&mut audio_commands, address, 0, note_frequency(note));
+                set_input(&mut audio_commands, address, 1, 1.0);
+                set_input(&mut audio_commands, address, 2, velocity);
+            }
+            // The last note's frequency and velocity stay put, so envelopes can fade out at the same pitch
+            None => set_input(&mut audio_commands, address, 1, 0.0),
+        }
+        audio_operations.record(AudioOperation::SetInput, entity);
+    }
+}
+
+// Keys released outside Interact mode would otherwise never send their note off
+fn release_notes(mut keyboard: ResMut<VirtualKeyboard>, mut note_events: EventWriter<NoteEvent>) {
+    for (_, note) in keyboard.keys.drain() {
+        note_events.send(NoteEvent::Off { note });
+    }
+    if let Some(note) = keyboard.pointer_note.take() {
+        note_events.send(NoteEvent::Off { note });
+    }
+}
@@ -2,6 +2,16 @@ pub mod ui;
 pub mod graph;
 pub mod camera;
 pub mod helper;
+pub mod node;
+pub mod patch;
+pub mod render;
+pub mod history;
+pub mod clipboard;
+pub mod meter;
+pub mod scope;
+pub mod perform;
+pub mod keyboard;
+pub mod midi;
 mod audio;
 
 pub use audio::*;
@@ -24,6 +34,15 @@ pub enum AppSet {
     GraphManagement,
 }
 
+/// Every node kind `AppPlugins` registers, for rendering patches without an app.
+pub fn node_registry() -> node::NodeRegistry {
+    let mut registry = node::NodeRegistry::default();
+    scope::register_nodes(&mut registry);
+    keyboard::register_nodes(&mut registry);
+    midi::register_nodes(&mut registry);
+    registry
+}
+
 pub struct AppPlugins;
 
 impl PluginGroup for AppPlugins {
@@ -34,7 +53,17 @@ impl PluginGroup for AppPlugins {
             .add(HelperPlugin)
             .add(EguiPlugin)
             .add(ui::UiPlugin)
+            .add(node::NodePlugin)
             .add(graph::GraphPlugin)
+            .add(patch::PatchPlugin)
+            .add(history::HistoryPlugin)
+            .add(clipboard::ClipboardPlugin)
+            .add(meter::MeterPlugin)
+            .add(scope::ScopePlugin)
+            .add(perform::PerformPlugin)
+            .add(keyboard::KeyboardPlugin)
+            .add(midi::MidiPlugin)
+            .add(render::OfflineRenderPlugin)
             .add(camera::CameraPlugin)
     }
 }
@@ -0,0 +1,277 @@
+use std::sync::{Arc, atomic::{AtomicU32, Ordering}};
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_prototype_lyon::prelude::{ShapeBundle, GeometryBuilder, Fill, Stroke};
+use bevy_prototype_lyon::shapes;
+use knyst::{prelude::*, graph::NodeAddress, Resources};
+
+use crate::{
+    AppSet, AudioCommands, AudioRestarted,
+    graph::{Graph, Edge, Vertex, VertexArea},
+    node::{NodeKind, AudioNodes, AudioOperation, AudioOperations},
+};
+
+// Levels below this are drawn as silence
+const METER_FLOOR_DB: f32 = -60.0;
+// Fraction of the displayed level kept per second, so that peaks fall off smoothly
+const METER_DECAY: f32 = 0.05;
+
+const GLOW_COLOUR: Color = Color::rgb(0.2, 0.9, 0.3);
+const GLOW_SPREAD: f32 = 6.0;
+const EDGE_WIDTH: f32 = 3.0;
+// Extra edge width at full level
+const EDGE_PULSE_WIDTH: f32 = 4.0;
+
+pub struct MeterPlugin;
+
+impl Plugin for MeterPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MeterSettings>()
+            .init_resource::<MeterNodes>()
+            .add_systems((
+                detach_meters,
+                attach_meters
+                    .run_if(|settings: Res<MeterSettings>| settings.enabled),
+                update_levels,
+                draw_levels,
+            )
+                .chain()
+                .after(AppSet::GraphManagement)
+            );
+    }
+}
+
+/// Metering costs a Knyst node per output, so it's off unless asked for.
+#[derive(Resource, Default)]
+pub struct MeterSettings {
+    pub enabled: bool,
+}
+
+/// The loudest peak and RMS seen by a meter since they were last taken.
+///
+/// Written by the audio thread and read by the main thread without locking. Non-negative
+/// floats order the same way as their bits, so the maximum can be kept with `fetch_max`.
+#[derive(Default)]
+pub struct MeterLevels {
+    peak: AtomicU32,
+    rms: AtomicU32,
+}
+
+impl MeterLevels {
+    fn record(&self, peak: f32, rms: f32) {
+        self.peak.fetch_max(peak.to_bits(), Ordering::Relaxed);
+        self.rms.fetch_max(rms.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Returns `(peak, rms)` and resets them.
+    pub fn take(&self) -> (f32, f32) {
+        (
+            f32::from_bits(self.peak.swap(0, Ordering::Relaxed)),
+            f32::from_bits(self.rms.swap(0, Ordering::Relaxed)),
+        )
+    }
+}
+
+struct MeterGen {
+    levels: Arc<MeterLevels>,
+}
+
+impl Gen for MeterGen {
+    fn process(&mut self, ctx: GenContext, _resources: &mut Resources) -> GenState {
+        let block_size = ctx.block_size();
+        let mut peak = 0.0_f32;
+        let mut sum_of_squares = 0.0;
+        for i in 0..block_size {
+            let sample = ctx.inputs.read(0, i);
+            if !sample.is_finite() { continue; }
+            peak = peak.max(sample.abs());
+            sum_of_squares += sample * sample;
+        }
+        self.levels.record(peak, (sum_of_squares / block_size as f32).sqrt());
+        GenState::Continue
+    }
+
+    fn num_inputs(&self) -> usize { 1 }
+
+    fn num_outputs(&self) -> usize { 0 }
+
+    fn name(&self) -> &'static str { "Meter" }
+}
+
+/// The meter nodes tapping each vertex's outputs.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct MeterNodes(HashMap<Entity, Vec<NodeAddress>>);
+
+/// The displayed level of each of a vertex's outputs, between 0 and 1.
+#[derive(Component, Default)]
+pub struct SignalLevels {
+    meters: Vec<Arc<MeterLevels>>,
+    pub peaks: Vec<f32>,
+    pub rms: Vec<f32>,
+}
+
+impl SignalLevels {
+    pub fn max_peak(&self) -> f32 {
+        self.peaks.iter().copied().fold(0.0, f32::max)
+    }
+}
+
+#[derive(Component)]
+struct MeterGlow;
+
+fn normalized_level(amplitude: f32) -> f32 {
+    if amplitude <= 0.0 { return 0.0; }
+    let db = 20.0 * amplitude.log10();
+    ((db - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0)
+}
+
+fn free_meters(
+    commands: &mut Commands,
+    audio_commands: &mut AudioCommands,
+    audio_operations: &mut AudioOperations,
+    entity: Entity,
+    addresses: Vec<NodeAddress>,
+) {
+    for address in addresses {
+        audio_commands.free_node(address);
+        audio_operations.record(AudioOperation::Free, entity);
+    }
+    if let Some(mut entity_commands) = commands.get_entity(entity) {
+        entity_commands.remove::<SignalLevels>();
+    }
+}
+
+// Frees meters whose vertex is gone or changed kind, or all of them once metering is turned off
+fn detach_meters(
+    mut commands: Commands,
+    settings: Res<MeterSettings>,
+    mut restarts: EventReader<AudioRestarted>,
+    audio_commands: Option<ResMut<AudioCommands>>,
+    mut audio_operations: ResMut<AudioOperations>,
+    mut meter_nodes: ResMut<MeterNodes>,
+    mut removed_vertices: RemovedComponents<Vertex>,
+    changed_kinds: Query<Entity, Changed<NodeKind>>,
+    glows: Query<(Entity, &Parent), With<MeterGlow>>,
+) {
+    let Some(mut audio_commands) = audio_commands else { return };
+
+    let detached: Vec<Entity> = if !restarts.is_empty() {
+        restarts.clear();
+        // The restarted audio graph no longer has any meters to free
+        let detached = meter_nodes.keys().copied().collect();
+        for entity in meter_nodes.keys() {
+            if let Some(mut entity_commands) = commands.get_entity(*entity) {
+                entity_commands.remove::<SignalLevels>();
+            }
+        }
+        meter_nodes.clear();
+        detached
+    }
+    else if !settings.enabled {
+        meter_nodes.keys().copied().collect()
+    }
+    else {
+        removed_vertices.iter()
+            .chain(changed_kinds.iter())
+            .filter(|entity| meter_nodes.contains_key(entity))
+            .collect()
+    };
+
+    for entity in detached.iter() {
+        if let Some(addresses) = meter_nodes.remove(entity) {
+            free_meters(&mut commands, &mut audio_commands, &mut audio_operations, *entity, addresses);
+        }
+    }
+    for (glow, parent) in glows.iter() {
+        if detached.contains(&parent.get()) {
+            commands.entity(glow).despawn_recursive();
+        }
+    }
+}
+
+fn attach_meters(
+    mut commands: Commands,
+    audio_commands: Option<ResMut<AudioCommands>>,
+    audio_nodes: Res<AudioNodes>,
+    mut audio_operations: ResMut<AudioOperations>,
+    mut meter_nodes: ResMut<MeterNodes>,
+    vertices: Query<(Entity, &NodeKind, &VertexArea), With<Vertex>>,
+) {
+    let Some(mut audio_commands) = audio_commands else { return };
+
+    for (entity, kind, area) in vertices.iter() {
+        if meter_nodes.contains_key(&entity) || kind.num_outputs() == 0 { continue; }
+        let Some(address) = audio_nodes.get(&entity) else { continue };
+
+        let mut addresses = Vec::new();
+        let mut levels = SignalLevels::default();
+        for output in 0..kind.num_outputs() {
+            let meter = Arc::new(MeterLevels::default());
+            let meter_address = audio_commands.push(MeterGen { levels: meter.clone() }, inputs!());
+            audio_commands.connect(address.to(&meter_address).from_index(output).to_index(0));
+            // Errors from a vertex's meters are blamed on the vertex
+            audio_operations.record(AudioOperation::Push, entity);
+            audio_operations.record(AudioOperation::Connect, entity);
+            addresses.push(meter_address);
+            levels.meters.push(meter);
+            levels.peaks.push(0.0);
+            levels.rms.push(0.0);
+        }
+        meter_nodes.insert(entity, addresses);
+
+        let glow = shapes::RegularPolygon {
+            sides: 4,
+            feature: shapes::RegularPolygonFeature::SideLength(2.0 * (area.half_extend() + GLOW_SPREAD)),
+            ..default()
+        };
+        commands.entity(entity)
+            .insert(levels)
+            .with_children(|parent| {
+                parent.spawn((
+                    MeterGlow,
+                    ShapeBundle {
+                        path: GeometryBuilder::build_as(&glow),
+                        transform: Transform::from_xyz(0.0, 0.0, -0.1),
+                        ..default()
+                    },
+                    Fill::color(GLOW_COLOUR.with_a(0.0)),
+                ));
+            });
+    }
+}
+
+fn update_levels(time: Res<Time>, mut levels: Query<&mut SignalLevels>) {
+    let decay = METER_DECAY.powf(time.delta_seconds());
+    for mut levels in levels.iter_mut() {
+        let levels = &mut *levels;
+        for ((meter, peak), rms) in levels.meters.iter().zip(levels.peaks.iter_mut()).zip(levels.rms.iter_mut()) {
+            let (new_peak, new_rms) = meter.take();
+            *peak = normalized_level(new_peak).max(*peak * decay);
+            *rms = normalized_level(new_rms).max(*rms * decay);
+        }
+    }
+}
+
+fn draw_levels(
+    graph: Res<Graph>,
+    levels: Query<&SignalLevels>,
+    mut glows: Query<(&Parent, &mut Fill), With<MeterGlow>>,
+    mut edges: Query<(Entity, &mut Stroke), With<Edge>>,
+) {
+    for (parent, mut fill) in glows.iter_mut() {
+        let Ok(levels) = levels.get(parent.get()) else { continue };
+        let alpha = 0.8 * levels.max_peak();
+        if (fill.color.a() - alpha).abs() > 0.01 {
+            fill.color.set_a(alpha);
+        }
+    }
+    for (entity, mut stroke) in edges.iter_mut() {
+        let level = graph.directed_edge(&entity)
+            .and_then(|edge| levels.get(edge.source).ok()?.peaks.get(edge.output).copied())
+            .unwrap_or(0.0);
+        let width = EDGE_WIDTH + EDGE_PULSE_WIDTH * level;
+        if (stroke.options.line_width - width).abs() > 0.1 {
+            stroke.options.line_width = width;
+        }
+    }
+}
@@ -0,0 +1,601 @@
+use std::sync::{Mutex, mpsc};
+
+use bevy::{prelude::*, utils::HashMap};
+use knyst::{prelude::*, graph::NodeAddress, controller::KnystCommands, Resources};
+use midir::{Ignore, MidiInputConnection};
+
+use crate::{
+    AppSet, AudioCommands, AudioRestarted,
+    graph::Vertex,
+    keyboard::note_frequency,
+    node::{NodeKind, NodeParameters, NodeRegistry, NodeType, RegisterNodeType, ParameterSpec, AudioNodes, AudioOperation, AudioOperations, set_input},
+    perform::{ParameterBinding, ParameterSmoothers, from_fraction},
+    ui::Notifications,
+};
+
+const MIDI_CLIENT_NAME: &str = "project";
+const MIDI_CHANNELS: usize = 16;
+const MIDI_CLOCKS_PER_BEAT: u32 = 24;
+
+// The MIDI node's parameters come first, then the hidden inputs it copies to its outputs
+const MIDI_PARAMETERS: usize = 2;
+const MIDI_OUTPUTS: usize = 6;
+
+pub struct MidiPlugin;
+
+impl Plugin for MidiPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_node_types(register_nodes)
+            .init_resource::<MidiInput>()
+            .init_resource::<MidiState>()
+            .init_resource::<MidiLearn>()
+            .add_event::<MidiMessage>()
+            .add_event::<ConnectMidi>()
+            .add_systems((
+                connect_midi,
+                receive_midi,
+            )
+                .chain()
+                .in_base_set(CoreSet::PreUpdate)
+            )
+            .add_systems((
+                prune_cc_bindings,
+                apply_cc_bindings,
+            )
+                .chain()
+                .after(AppSet::Ui)
+                .before(AppSet::GraphManagement)
+            )
+            .add_system(drive_midi_nodes.after(AppSet::GraphManagement));
+    }
+}
+
+/// A channel message or a real-time message. Channels count from 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MidiMessage {
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    /// `value` is between -1 and 1.
+    PitchBend { channel: u8, value: f32 },
+    Clock,
+    Start,
+    Continue,
+    Stop,
+}
+
+impl MidiMessage {
+    /// Returns `None` for messages that aren't handled, like system exclusive.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let (&status, data) = bytes.split_first()?;
+        let channel = status & 0x0f;
+        let message = match (status & 0xf0, data) {
+            // A note on with no velocity is a note off
+            (0x90, &[note, 0, ..]) | (0x80, &[note, _, ..]) => MidiMessage::NoteOff { channel, note },
+            (0x90, &[note, velocity, ..]) => MidiMessage::NoteOn { channel, note, velocity },
+            (0xb0, &[controller, value, ..]) => MidiMessage::ControlChange { channel, controller, value },
+            (0xe0, &[lsb, msb, ..]) => {
+                let raw = ((msb as u16) << 7 | lsb as u16) as f32;
+                MidiMessage::PitchBend { channel, value: (raw - 8192.0) / 8192.0 }
+            }
+            _ => match status {
+                0xf8 => MidiMessage::Clock,
+                0xfa => MidiMessage::Start,
+                0xfb => MidiMessage::Continue,
+                0xfc => MidiMessage::Stop,
+                _ => return None,
+            },
+        };
+        Some(message)
+    }
+
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::PitchBend { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MidiPort {
+    #[default]
+    None,
+    Device(String),
+    /// A port of our own that other programs can send to, e.g. to loop back a sequencer. Not available on Windows.
+    Virtual,
+}
+
+impl MidiPort {
+    pub fn name(&self) -> &str {
+        match self {
+            MidiPort::None => "None",
+            MidiPort::Device(name) => name.as_str(),
+            MidiPort::Virtual => "Virtual port",
+        }
+    }
+}
+
+/// Connects to a MIDI port, closing the current one.
+pub struct ConnectMidi(pub MidiPort);
+
+/// Receives messages from the connected port, and from anything given a `sender`.
+#[derive(Resource)]
+pub struct MidiInput {
+    sender: Mutex<mpsc::Sender<MidiMessage>>,
+    receiver: Mutex<mpsc::Receiver<MidiMessage>>,
+    connection: Mutex<Option<MidiInputConnection<()>>>,
+    port: MidiPort,
+}
+
+impl Default for MidiInput {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        MidiInput {
+            sender: Mutex::new(sender),
+            receiver: Mutex::new(receiver),
+            connection: Mutex::new(None),
+            port: MidiPort::None,
+        }
+    }
+}
+
+impl MidiInput {
+    pub fn port(&self) -> &MidiPort {
+        &self.port
+    }
+
+    /// Messages sent here are handled as if they came from the connected port, so tests and other plugins
+    /// can stand in for a MIDI device.
+    pub fn sender(&self) -> mpsc::Sender<MidiMessage> {
+        self.sender.lock().unwrap().clone()
+    }
+}
+
+pub fn midi_input_port_names() -> Vec<String> {
+    let Ok(midi_in) = midir::MidiInput::new(MIDI_CLIENT_NAME) else { return Vec::new() };
+    midi_in.ports().iter()
+        .filter_map(|port| midi_in.port_name(port).ok())
+        .collect()
+}
+
+fn open_port(port: &MidiPort, sender: mpsc::Sender<MidiMessage>) -> Result<Option<MidiInputConnection<()>>, String> {
+    if *port == MidiPort::None { return Ok(None); }
+
+    let mut midi_in = midir::MidiInput::new(MIDI_CLIENT_NAME).map_err(|e| e.to_string())?;
+    // Clock messages are wanted
+    midi_in.ignore(Ignore::SysexAndActiveSense);
+    let callback = move |_timestamp: u64, bytes: &[u8], _: &mut ()| {
+        if let Some(message) = MidiMessage::parse(bytes) {
+            let _ = sender.send(message);
+        }
+    };
+
+    match port {
+        MidiPort::None => Ok(None),
+        MidiPort::Device(name) => {
+            let device = midi_in.ports().into_iter()
+                .find(|device| midi_in.port_name(device).ok().as_deref() == Some(name.as_str()))
+                .ok_or_else(|| format!("no MIDI input named {name}"))?;
+            midi_in.connect(&device, "input", callback, ())
+                .map(Some)
+                .map_err(|e| e.to_string())
+        }
+        #[cfg(unix)]
+        MidiPort::Virtual => {
+            use midir::os::unix::VirtualInput;
+            midi_in.create_virtual(MIDI_CLIENT_NAME, callback, ())
+                .map(Some)
+                .map_err(|e| e.to_string())
+        }
+        #[cfg(not(unix))]
+        MidiPort::Virtual => Err("virtual MIDI ports aren't supported on this platform".to_string()),
+    }
+}
+
+fn connect_midi(
+    mut connect_events: EventReader<ConnectMidi>,
+    mut midi_input: ResMut<MidiInput>,
+    notifications: Option<ResMut<Notifications>>,
+) {
+    let Some(ConnectMidi(port)) = connect_events.iter().last() else { return };
+
+    // Dropping the connection closes it, freeing the device in case it's opened again
+    midi_input.connection.get_mut().unwrap().take();
+    let sender = midi_input.sender();
+    match open_port(port, sender) {
+        Ok(connection) => {
+            *midi_input.connection.get_mut().unwrap() = connection;
+            midi_input.port = port.clone();
+        }
+        Err(e) => {
+            midi_input.port = MidiPort::None;
+            warn!("Could not open MIDI port {}: {e}", port.name());
+            if let Some(mut notifications) = notifications {
+                notifications.push(format!("Could not open MIDI port {}: {e}", port.name()));
+            }
+        }
+    }
+}
+
+fn receive_midi(midi_input: Res<MidiInput>, mut midi_events: EventWriter<MidiMessage>) {
+    for message in midi_input.receiver.lock().unwrap().try_iter() {
+        midi_events.send(message);
+    }
+}
+
+pub fn register_nodes(registry: &mut NodeRegistry) {
+    registry.register(MidiNode);
+}
+
+/// Outputs the last note, pitch bend, a chosen CC and the clock received on a MIDI channel.
+pub struct MidiNode;
+
+impl NodeType for MidiNode {
+    fn name(&self) -> &'static str { "MIDI In" }
+
+    fn category(&self) -> &'static str { "Sources" }
+
+    // A channel of 0 listens to every channel
+    fn inputs(&self) -> &'static [&'static str] { &["channel", "cc"] }
+
+    // They choose which values are sent to the node, which happens outside the audio graph
+    fn ports(&self) -> &'static [&'static str] { &[] }
+
+    fn outputs(&self) -> &'static [&'static str] { &["freq", "gate", "velocity", "bend", "cc", "clock"] }
+
+    fn default_inputs(&self) -> &'static [f32] { &[0.0, 1.0] }
+
+    fn parameter_spec(&self, index: usize) -> ParameterSpec {
+        match index {
+            0 => ParameterSpec { range: 0.0..=MIDI_CHANNELS as f32, unit: "", logarithmic: false },
+            _ => ParameterSpec { range: 0.0..=127.0, unit: "", logarithmic: false },
+        }
+    }
+
+    fn construct(&self, commands: &mut KnystCommands) -> NodeAddress {
+        commands.push(MidiGen, inputs!())
+    }
+}
+
+struct MidiGen;
+
+impl Gen for MidiGen {
+    fn process(&mut self, mut ctx: GenContext, _resources: &mut Resources) -> GenState {
+        for output in 0..MIDI_OUTPUTS {
+            for i in 0..ctx.block_size() {
+                let value = ctx.inputs.read(MIDI_PARAMETERS + output, i);
+                ctx.outputs.write(value, output, i);
+            }
+        }
+        GenState::Continue
+    }
+
+    fn num_inputs(&self) -> usize { MIDI_PARAMETERS + MIDI_OUTPUTS }
+
+    fn num_outputs(&self) -> usize { MIDI_OUTPUTS }
+
+    fn name(&self) -> &'static str { "MidiIn" }
+}
+
+#[derive(Clone)]
+struct ChannelState {
+    held: Vec<(u8, f32)>,
+    // Kept after release, so envelopes can fade out at the same pitch
+    last_note: (u8, f32),
+    bend: f32,
+    controllers: HashMap<u8, f32>,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        ChannelState { held: Vec::new(), last_note: (69, 0.0), bend: 0.0, controllers: HashMap::default() }
+    }
+}
+
+/// What MIDI nodes output, following every message received.
+#[derive(Resource)]
+pub struct MidiState {
+    // Index 0 follows every channel, and channel n is at index n + 1
+    channels: Vec<ChannelState>,
+    clock_ticks: u32,
+    running: bool,
+}
+
+impl Default for MidiState {
+    fn default() -> Self {
+        MidiState { channels: vec![ChannelState::default(); MIDI_CHANNELS + 1], clock_ticks: 0, running: false }
+    }
+}
+
+impl MidiState {
+    pub fn handle(&mut self, message: MidiMessage) {
+        match message {
+            MidiMessage::Clock => self.clock_ticks = self.clock_ticks.wrapping_add(1),
+            MidiMessage::Start => {
+                self.clock_ticks = 0;
+                self.running = true;
+            }
+            MidiMessage::Continue => self.running = true,
+            MidiMessage::Stop => self.running = false,
+            _ => {}
+        }
+        let Some(channel) = message.channel() else { return };
+
+        for state in [0, channel as usize + 1] {
+            let state = &mut self.channels[state];
+            match message {
+                MidiMessage::NoteOn { note, velocity, .. } => {
+                    let velocity = velocity as f32 / 127.0;
+                    state.held.retain(|(held, _)| *held != note);
+                    state.held.push((note, velocity));
+                    state.last_note = (note, velocity);
+                }
+                MidiMessage::NoteOff { note, .. } => {
+                    state.held.retain(|(held, _)| *held != note);
+                    if let Some(last) = state.held.last() {
+                        state.last_note = *last;
+                    }
+                }
+                MidiMessage::ControlChange { controller, value, .. } => {
+                    state.controllers.insert(controller, value as f32 / 127.0);
+                }
+                MidiMessage::PitchBend { value, .. } => state.bend = value,
+                _ => {}
+            }
+        }
+    }
+
+    /// The values of a MIDI node's outputs, where `channel` 0 is every channel.
+    pub fn outputs(&self, channel: usize, controller: u8) -> [f32; MIDI_OUTPUTS] {
+        let state = &self.channels[channel.min(MIDI_CHANNELS)];
+        let (note, velocity) = state.last_note;
+        let gate = if state.held.is_empty() { 0.0 } else { 1.0 };
+        // High for the first half of each beat
+        let clock = if self.running && self.clock_ticks % MIDI_CLOCKS_PER_BEAT < MIDI_CLOCKS_PER_BEAT / 2 { 1.0 } else { 0.0 };
+        [
+            note_frequency(note),
+            gate,
+            velocity,
+            state.bend,
+            state.controllers.get(&controller).copied().unwrap_or(0.0),
+            clock,
+        ]
+    }
+}
+
+fn drive_midi_nodes(
+    mut midi_events: EventReader<MidiMessage>,
+    mut restarts: EventReader<AudioRestarted>,
+    mut state: ResMut<MidiState>,
+    audio_commands: Option<ResMut<AudioCommands>>,
+    audio_nodes: Res<AudioNodes>,
+    mut audio_operations: ResMut<AudioOperations>,
+    vertices: Query<(Entity, Ref<NodeKind>, Ref<NodeParameters>)>,
+) {
+    let received = !midi_events.is_empty();
+    for message in midi_events.iter() {
+        state.handle(*message);
+    }
+    // Restarting pushes fresh nodes, whose hidden inputs start at 0
+    let restarted = !restarts.is_empty();
+    restarts.clear();
+    let Some(mut audio_commands) = audio_commands else { return };
+
+    for (entity, kind, parameters) in vertices.iter() {
+        if kind.name() != MidiNode.name() { continue; }
+        // Changing the channel or CC changes the outputs too, and a changed kind means a new node
+        if !received && !restarted && !kind.is_changed() && !parameters.is_changed() { continue; }
+        let Some(address) = audio_nodes.get(&entity) else { continue };
+
+        let channel = parameters.first().map_or(0, |channel| channel.round() as usize);
+        let controller = parameters.get(1).map_or(0, |controller| controller.round() as u8);
+        for (output, value) in state.outputs(channel, controller).into_iter().enumerate() {
+            set_input(&mut audio_commands, address, MIDI_PARAMETERS + output, value);
+        }
+        audio_operations.record(AudioOperation::SetInput, entity);
+    }
+}
+
+/// A CC bound to a parameter with MIDI learn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CcBinding {
+    pub channel: u8,
+    pub controller: u8,
+    pub parameter: ParameterBinding,
+}
+
+/// The CCs bound to parameters, and the parameter waiting for the next CC to arrive.
+#[derive(Resource, Default)]
+pub struct MidiLearn {
+    pub listening: Option<ParameterBinding>,
+    pub bindings: Vec<CcBinding>,
+}
+
+impl MidiLearn {
+    pub fn controller_of(&self, parameter: ParameterBinding) -> Option<u8> {
+        self.bindings.iter()
+            .find(|binding| binding.parameter == parameter)
+            .map(|binding| binding.controller)
+    }
+
+    pub fn forget(&mut self, parameter: ParameterBinding) {
+        self.bindings.retain(|binding| binding.parameter != parameter);
+    }
+}
+
+// Drops bindings whose vertex is gone or no longer has the same inputs
+fn prune_cc_bindings(
+    mut learn: ResMut<MidiLearn>,
+    mut removed_vertices: RemovedComponents<Vertex>,
+    changed_kinds: Query<Entity, (With<Vertex>, Changed<NodeKind>)>,
+) {
+    let stale: Vec<Entity> = removed_vertices.iter().chain(changed_kinds.iter()).collect();
+    if stale.is_empty() { return; }
+
+    learn.bindings.retain(|binding| !stale.contains(&binding.parameter.vertex));
+    if learn.listening.map_or(false, |parameter| stale.contains(&parameter.vertex)) {
+        learn.listening = None;
+    }
+}
+
+fn apply_cc_bindings(
+    mut midi_events: EventReader<MidiMessage>,
+    mut learn: ResMut<MidiLearn>,
+    mut smoothers: ResMut<ParameterSmoothers>,
+    vertices: Query<(&NodeKind, &NodeParameters), With<Vertex>>,
+) {
+    for message in midi_events.iter() {
+        let MidiMessage::ControlChange { channel, controller, value } = *message else { continue };
+        if let Some(parameter) = learn.listening.take() {
+            learn.forget(parameter);
+            learn.bindings.push(CcBinding { channel, controller, parameter });
+        }
+
+        for binding in learn.bindings.iter().filter(|binding| binding.channel == channel && binding.controller == controller) {
+            let parameter = binding.parameter;
+            let Ok((kind, parameters)) = vertices.get(parameter.vertex) else { continue };
+            let Some(current) = parameters.get(parameter.input).copied() else { continue };
+            let spec = kind.parameter_spec(parameter.input);
+            smoothers.glide(parameter, current, from_fraction(&spec, value as f32 / 127.0), &spec);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::OscillatorNode;
+
+    fn parse(bytes: &[u8]) -> MidiMessage {
+        MidiMessage::parse(bytes).unwrap()
+    }
+
+    fn state_after(bytes: &[&[u8]]) -> MidiState {
+        let mut state = MidiState::default();
+        for message in bytes {
+            state.handle(parse(message));
+        }
+        state
+    }
+
+    // Outputs are freq, gate, velocity, bend, cc, clock
+    const FREQ: usize = 0;
+    const GATE: usize = 1;
+    const BEND: usize = 3;
+    const CC: usize = 4;
+    const CLOCK: usize = 5;
+
+    #[test]
+    fn note_on_without_velocity_is_note_off() {
+        assert_eq!(parse(&[0x91, 60, 0]), MidiMessage::NoteOff { channel: 1, note: 60 });
+        assert_eq!(parse(&[0x81, 60, 64]), MidiMessage::NoteOff { channel: 1, note: 60 });
+        assert_eq!(parse(&[0x91, 60, 100]), MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 });
+
+        let state = state_after(&[&[0x90, 60, 100], &[0x90, 60, 0]]);
+        assert_eq!(state.outputs(0, 0)[GATE], 0.0);
+    }
+
+    #[test]
+    fn pitch_bend_is_scaled_to_plus_minus_one() {
+        let bend = |lsb, msb| match parse(&[0xe3, lsb, msb]) {
+            MidiMessage::PitchBend { channel: 3, value } => value,
+            other => panic!("expected pitch bend, got {other:?}"),
+        };
+        assert_eq!(bend(0, 0), -1.0);
+        assert_eq!(bend(0, 0x40), 0.0);
+        assert!((bend(0x7f, 0x7f) - 1.0).abs() < 0.001);
+
+        let state = state_after(&[&[0xe3, 0, 0x60]]);
+        assert_eq!(state.outputs(4, 0)[BEND], 0.5);
+        assert_eq!(state.outputs(1, 0)[BEND], 0.0);
+    }
+
+    #[test]
+    fn clock_is_high_for_the_first_half_of_each_beat() {
+        assert_eq!(parse(&[0xf8]), MidiMessage::Clock);
+        assert_eq!(parse(&[0xfa]), MidiMessage::Start);
+        assert_eq!(parse(&[0xfb]), MidiMessage::Continue);
+        assert_eq!(parse(&[0xfc]), MidiMessage::Stop);
+        assert_eq!(MidiMessage::parse(&[0xf0, 0x7e, 0xf7]), None);
+
+        let mut state = state_after(&[&[0xf8]]);
+        assert_eq!(state.outputs(0, 0)[CLOCK], 0.0, "clock runs only after start");
+        state.handle(parse(&[0xfa]));
+        assert_eq!(state.outputs(0, 0)[CLOCK], 1.0);
+        for _ in 0..MIDI_CLOCKS_PER_BEAT / 2 {
+            state.handle(parse(&[0xf8]));
+        }
+        assert_eq!(state.outputs(0, 0)[CLOCK], 0.0);
+        for _ in 0..MIDI_CLOCKS_PER_BEAT / 2 {
+            state.handle(parse(&[0xf8]));
+        }
+        assert_eq!(state.outputs(0, 0)[CLOCK], 1.0);
+        state.handle(parse(&[0xfc]));
+        assert_eq!(state.outputs(0, 0)[CLOCK], 0.0);
+    }
+
+    #[test]
+    fn channels_are_filtered_and_zero_hears_every_channel() {
+        // Note on and CC 7 on the third channel, which MIDI nodes call channel 3
+        let state = state_after(&[&[0x92, 60, 100], &[0xb2, 7, 127]]);
+        assert_eq!(state.outputs(3, 7)[GATE], 1.0);
+        assert_eq!(state.outputs(3, 7)[CC], 1.0);
+        assert_eq!(state.outputs(0, 7)[GATE], 1.0);
+        assert_eq!(state.outputs(0, 7)[CC], 1.0);
+        assert_eq!(state.outputs(1, 7)[GATE], 0.0);
+        assert_eq!(state.outputs(1, 7)[CC], 0.0);
+        assert_eq!(state.outputs(3, 1)[CC], 0.0);
+    }
+
+    #[test]
+    fn releasing_a_note_falls_back_to_the_one_still_held() {
+        let mut state = state_after(&[&[0x90, 60, 100], &[0x90, 64, 100]]);
+        assert_eq!(state.outputs(0, 0)[FREQ], note_frequency(64));
+
+        state.handle(parse(&[0x80, 64, 0]));
+        assert_eq!(state.outputs(0, 0)[FREQ], note_frequency(60));
+        assert_eq!(state.outputs(0, 0)[GATE], 1.0);
+
+        state.handle(parse(&[0x80, 60, 0]));
+        assert_eq!(state.outputs(0, 0)[FREQ], note_frequency(60), "frequency is kept after release");
+        assert_eq!(state.outputs(0, 0)[GATE], 0.0);
+    }
+
+    #[test]
+    fn learned_cc_moves_its_parameter() {
+        let mut app = App::new();
+        app.init_resource::<MidiInput>()
+            .init_resource::<MidiLearn>()
+            .init_resource::<ParameterSmoothers>()
+            .add_event::<MidiMessage>()
+            .add_systems((receive_midi, apply_cc_bindings).chain());
+        let kind = NodeKind::new(OscillatorNode);
+        let vertex = app.world.spawn((Vertex, NodeParameters::new(&kind), kind)).id();
+        let parameter = ParameterBinding { vertex, input: 0 };
+        // Stands in for a MIDI device
+        let sender = app.world.resource::<MidiInput>().sender();
+
+        app.world.resource_mut::<MidiLearn>().listening = Some(parameter);
+        sender.send(parse(&[0xb0, 74, 0])).unwrap();
+        app.update();
+        let learn = app.world.resource::<MidiLearn>();
+        assert_eq!(learn.listening, None);
+        assert_eq!(learn.bindings, vec![CcBinding { channel: 0, controller: 74, parameter }]);
+
+        sender.send(parse(&[0xb0, 74, 127])).unwrap();
+        app.update();
+        let top = *OscillatorNode.parameter_spec(0).range.end();
+        let target = |app: &App| app.world.resource::<ParameterSmoothers>().target(parameter).unwrap();
+        assert!((target(&app) - top).abs() < 0.1);
+
+        // Other controllers and channels leave it alone
+        sender.send(parse(&[0xb0, 75, 0])).unwrap();
+        sender.send(parse(&[0xb1, 74, 0])).unwrap();
+        app.update();
+        assert!((target(&app) - top).abs() < 0.1);
+    }
+}
@@ -0,0 +1,304 @@
+use std::{collections::VecDeque, fmt, ops::RangeInclusive, sync::Arc};
+
+use bevy::{prelude::*, utils::HashMap};
+use knyst::{
+    prelude::*,
+    graph::{NodeAddress, ParameterChange, Connection},
+    controller::KnystCommands,
+    wavetable::WavetableOscillatorOwned,
+};
+
+pub struct NodePlugin;
+
+impl Plugin for NodePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NodeRegistry>();
+    }
+}
+
+/// A kind of audio node that vertices can stand for.
+///
+/// Downstream crates implement this and add it with `RegisterNodeType::register_node_type`.
+pub trait NodeType: Send + Sync + 'static {
+    /// Unique among registered node types; patches refer to node types by name.
+    fn name(&self) -> &'static str;
+
+    fn category(&self) -> &'static str;
+
+    fn inputs(&self) -> &'static [&'static str];
+
+    fn outputs(&self) -> &'static [&'static str];
+
+    /// The inputs edges can connect to, all of them unless some are only ever set as parameters.
+    fn ports(&self) -> &'static [&'static str] {
+        self.inputs()
+    }
+
+    /// One value per input.
+    fn default_inputs(&self) -> &'static [f32];
+
+    fn parameter_spec(&self, _index: usize) -> ParameterSpec {
+        ParameterSpec::default()
+    }
+
+    /// Pushes a fresh Knyst node of this type. Its inputs are set right after.
+    fn construct(&self, commands: &mut KnystCommands) -> NodeAddress;
+
+    fn num_outputs(&self) -> usize {
+        self.outputs().len()
+    }
+
+    fn input_index(&self, input: &str) -> Option<usize> {
+        self.inputs().iter().position(|name| *name == input)
+    }
+}
+
+/// The kind of audio node a vertex stands for.
+#[derive(Component, Clone, Deref)]
+pub struct NodeKind(pub Arc<dyn NodeType>);
+
+impl NodeKind {
+    pub fn new(node_type: impl NodeType) -> Self {
+        NodeKind(Arc::new(node_type))
+    }
+
+    /// Pushes a fresh Knyst node of this kind, with its inputs set to `parameters`.
+    pub fn push(&self, commands: &mut KnystCommands, parameters: &NodeParameters) -> NodeAddress {
+        let address = self.construct(commands);
+        for (index, value) in parameters.iter().enumerate() {
+            set_input(commands, &address, index, *value);
+        }
+        address
+    }
+}
+
+impl PartialEq for NodeKind {
+    fn eq(&self, other: &Self) -> bool {
+        self.name() == other.name()
+    }
+}
+
+impl Eq for NodeKind {}
+
+impl fmt::Debug for NodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("NodeKind").field(&self.name()).finish()
+    }
+}
+
+/// Every node kind that can be created, starting with the built-in ones.
+#[derive(Resource, Clone)]
+pub struct NodeRegistry {
+    kinds: Vec<NodeKind>,
+}
+
+impl Default for NodeRegistry {
+    fn default() -> Self {
+        let mut registry = NodeRegistry::empty();
+        registry.register(OscillatorNode);
+        registry.register(MultNode);
+        registry.register(OutputNode);
+        registry
+    }
+}
+
+impl NodeRegistry {
+    pub fn empty() -> Self {
+        NodeRegistry { kinds: Vec::new() }
+    }
+
+    /// Adds a node type, replacing any registered type with the same name.
+    pub fn register(&mut self, node_type: impl NodeType) {
+        let kind = NodeKind::new(node_type);
+        match self.kinds.iter_mut().find(|registered| **registered == kind) {
+            Some(registered) => *registered = kind,
+            None => self.kinds.push(kind),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<NodeKind> {
+        self.kinds.iter().find(|kind| kind.name() == name).cloned()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &NodeKind> {
+        self.kinds.iter()
+    }
+}
+
+pub trait RegisterNodeType {
+    fn register_node_type(&mut self, node_type: impl NodeType) -> &mut Self;
+
+    /// Registers the node types added by `register`, which can also build a registry without an app.
+    fn register_node_types(&mut self, register: fn(&mut NodeRegistry)) -> &mut Self;
+}
+
+impl RegisterNodeType for App {
+    fn register_node_type(&mut self, node_type: impl NodeType) -> &mut Self {
+        self.init_resource::<NodeRegistry>();
+        self.world.resource_mut::<NodeRegistry>().register(node_type);
+        self
+    }
+
+    fn register_node_types(&mut self, register: fn(&mut NodeRegistry)) -> &mut Self {
+        self.init_resource::<NodeRegistry>();
+        register(&mut self.world.resource_mut::<NodeRegistry>());
+        self
+    }
+}
+
+pub struct OscillatorNode;
+
+impl NodeType for OscillatorNode {
+    fn name(&self) -> &'static str { "Oscillator" }
+
+    fn category(&self) -> &'static str { "Sources" }
+
+    fn inputs(&self) -> &'static [&'static str] { &["freq"] }
+
+    fn outputs(&self) -> &'static [&'static str] { &["out"] }
+
+    fn default_inputs(&self) -> &'static [f32] { &[440.0] }
+
+    fn parameter_spec(&self, _index: usize) -> ParameterSpec {
+        ParameterSpec { range: 0.1..=20000.0, unit: " Hz", logarithmic: true }
+    }
+
+    fn construct(&self, commands: &mut KnystCommands) -> NodeAddress {
+        commands.push(WavetableOscillatorOwned::new(Wavetable::sine()), inputs!())
+    }
+}
+
+pub struct MultNode;
+
+impl NodeType for MultNode {
+    fn name(&self) -> &'static str { "Mult" }
+
+    fn category(&self) -> &'static str { "Math" }
+
+    fn inputs(&self) -> &'static [&'static str] { &["a", "b"] }
+
+    fn outputs(&self) -> &'static [&'static str] { &["out"] }
+
+    fn default_inputs(&self) -> &'static [f32] { &[0.0, 1.0] }
+
+    fn parameter_spec(&self, _index: usize) -> ParameterSpec {
+        ParameterSpec { range: -10.0..=10.0, unit: "", logarithmic: false }
+    }
+
+    fn construct(&self, commands: &mut KnystCommands) -> NodeAddress {
+        commands.push(Mult, inputs!())
+    }
+}
+
+/// Output nodes feed the first two graph outputs, so graphs they're pushed into need at least this many.
+pub const MIN_OUTPUT_CHANNELS: usize = 2;
+
+pub struct OutputNode;
+
+impl NodeType for OutputNode {
+    fn name(&self) -> &'static str { "Output" }
+
+    fn category(&self) -> &'static str { "Output" }
+
+    fn inputs(&self) -> &'static [&'static str] { &["left", "right"] }
+
+    fn outputs(&self) -> &'static [&'static str] { &[] }
+
+    fn default_inputs(&self) -> &'static [f32] { &[0.0, 0.0] }
+
+    fn construct(&self, commands: &mut KnystCommands) -> NodeAddress {
+        let address = commands.push(Bus(2), inputs!());
+        commands.connect(address.to_graph_out().from_index(0).to_index(0));
+        commands.connect(address.to_graph_out().from_index(1).to_index(1));
+        address
+    }
+}
+
+/// How an input's constant value is edited.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParameterSpec {
+    pub range: RangeInclusive<f32>,
+    pub unit: &'static str,
+    pub logarithmic: bool,
+}
+
+impl Default for ParameterSpec {
+    fn default() -> Self {
+        ParameterSpec { range: -1.0..=1.0, unit: "", logarithmic: false }
+    }
+}
+
+/// The constant value of each input of a vertex's node, in the order of `NodeType::inputs`.
+#[derive(Component, Clone, Debug, PartialEq, Deref, DerefMut)]
+pub struct NodeParameters(pub Vec<f32>);
+
+impl NodeParameters {
+    pub fn new(kind: &NodeKind) -> Self {
+        NodeParameters(kind.default_inputs().to_vec())
+    }
+}
+
+/// The connection from output `output` of `source` to the input named `input` of `sink`, if both ports exist.
+pub fn port_connection(
+    (source_kind, source): (&NodeKind, &NodeAddress),
+    output: usize,
+    (sink_kind, sink): (&NodeKind, &NodeAddress),
+    input: &str,
+) -> Option<Connection> {
+    if output >= source_kind.num_outputs() || !sink_kind.ports().contains(&input) { return None; }
+    let input_index = sink_kind.input_index(input)?;
+    Some(source.to(sink).from_index(output).to_index(input_index))
+}
+
+pub fn set_input(commands: &mut KnystCommands, address: &NodeAddress, index: usize, value: f32) {
+    commands.schedule_change(ParameterChange::now(address.input(index), value));
+}
+
+/// Knyst nodes owned by vertices in the graph.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct AudioNodes(pub HashMap<Entity, NodeAddress>);
+
+pub struct AudioConnection {
+    pub source: Entity,
+    pub sink: Entity,
+    pub connection: Connection,
+}
+
+/// Knyst connections owned by edges in the graph.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct AudioConnections(pub HashMap<Entity, AudioConnection>);
+
+// The number of operations remembered for attributing audio errors
+const AUDIO_OPERATIONS_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioOperation {
+    Push,
+    Free,
+    Connect,
+    Disconnect,
+    SetInput,
+}
+
+/// The most recent operations sent to the audio graph, and the vertex or edge that sent each.
+///
+/// Knyst reports errors without saying which node caused them, so they're attributed to
+/// the latest operation of a matching kind.
+#[derive(Resource, Default)]
+pub struct AudioOperations(VecDeque<(AudioOperation, Entity)>);
+
+impl AudioOperations {
+    pub fn record(&mut self, operation: AudioOperation, entity: Entity) {
+        self.0.push_back((operation, entity));
+        if self.0.len() > AUDIO_OPERATIONS_SIZE {
+            self.0.pop_front();
+        }
+    }
+
+    pub fn latest(&self, operations: &[AudioOperation]) -> Option<Entity> {
+        self.0.iter()
+            .rev()
+            .find(|(operation, _)| operations.contains(operation))
+            .map(|(_, entity)| *entity)
+    }
+}
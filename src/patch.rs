@@ -0,0 +1,402 @@
+use std::{fmt, fs, io, path::{Path, PathBuf}, collections::BTreeMap};
+
+use bevy::{prelude::*, math::Vec3Swizzles, utils::{HashMap, HashSet}};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::{
+    AppSet,
+    graph::{Graph, GraphSelection, Vertex, VertexName, VertexBundle, EdgeBuilder, VERTEX_HALF_EXTEND},
+    node::{NodeKind, NodeParameters, NodeRegistry},
+};
+
+/// The version written into newly saved patches.
+pub const PATCH_VERSION: u32 = MIGRATIONS.len() as u32 + 1;
+
+type Migration = fn(&mut Value) -> Result<(), PatchError>;
+
+// `MIGRATIONS[i]` upgrades a patch from version `i + 1` to version `i + 2`
+const MIGRATIONS: &[Migration] = &[];
+
+pub struct PatchPlugin;
+
+impl Plugin for PatchPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PatchFile>()
+            .add_event::<SavePatch>()
+            .add_event::<LoadPatch>()
+            .add_systems((
+                save_patch,
+                load_patch,
+            )
+                .after(AppSet::Ui)
+                .before(AppSet::GraphManagement)
+            );
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Patch {
+    pub version: u32,
+    pub vertices: Vec<PatchVertex>,
+    pub edges: Vec<PatchEdge>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PatchVertex {
+    pub id: usize,
+    pub name: String,
+    pub kind: String,
+    pub position: (f32, f32),
+    pub parameters: BTreeMap<String, f32>,
+}
+
+impl PatchVertex {
+    /// The vertex's node kind, and its parameters with defaults for any that are missing.
+    pub fn node(&self, registry: &NodeRegistry) -> Result<(NodeKind, NodeParameters), PatchError> {
+        let kind = registry.get(&self.kind)
+            .ok_or_else(|| PatchError::UnknownNodeKind(self.kind.clone()))?;
+        let parameters = NodeParameters(
+            kind.inputs().iter()
+                .zip(kind.default_inputs())
+                .map(|(input, default)| self.parameters.get(*input).copied().unwrap_or(*default))
+                .collect()
+        );
+        Ok((kind, parameters))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PatchEdge {
+    pub source: usize,
+    pub output: usize,
+    pub sink: usize,
+    pub input: String,
+}
+
+#[derive(Debug)]
+pub enum PatchError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    MissingVersion,
+    UnsupportedVersion(u64),
+    UnknownNodeKind(String),
+    DuplicateVertex(usize),
+    UnknownVertex(usize),
+    UnknownPort { vertex: usize, port: String },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::Io(e) => write!(f, "{e}"),
+            PatchError::Json(e) => write!(f, "invalid patch file: {e}"),
+            PatchError::MissingVersion => write!(f, "patch file has no version"),
+            PatchError::UnsupportedVersion(v) => write!(f, "unsupported patch version {v} (latest is {PATCH_VERSION})"),
+            PatchError::UnknownNodeKind(kind) => write!(f, "unknown node kind \"{kind}\""),
+            PatchError::DuplicateVertex(id) => write!(f, "more than one vertex has id {id}"),
+            PatchError::UnknownVertex(id) => write!(f, "edge refers to unknown vertex {id}"),
+            PatchError::UnknownPort { vertex, port } => write!(f, "edge refers to unknown port {port} of vertex {vertex}"),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+impl From<io::Error> for PatchError {
+    fn from(e: io::Error) -> Self {
+        PatchError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for PatchError {
+    fn from(e: serde_json::Error) -> Self {
+        PatchError::Json(e)
+    }
+}
+
+pub type PatchVertexQuery<'w, 's> = Query<'w, 's, (Entity, &'static VertexName, &'static NodeKind, &'static NodeParameters, &'static Transform), With<Vertex>>;
+
+impl Patch {
+    /// Collects every vertex with a node kind, and the edges between them.
+    pub fn collect(graph: &Graph, vertices: &PatchVertexQuery) -> Self {
+        Self::collect_from(graph, vertices, vertices.iter().map(|(entity, ..)| entity))
+    }
+
+    /// Collects the given vertices, and only the edges among them.
+    pub fn collect_from(graph: &Graph, vertices: &PatchVertexQuery, entities: impl IntoIterator<Item = Entity>) -> Self {
+        let mut ids = HashMap::new();
+        let mut patch_vertices = Vec::new();
+        for entity in entities {
+            let Ok((entity, name, kind, parameters, transform)) = vertices.get(entity) else { continue };
+            let id = patch_vertices.len();
+            ids.insert(entity, id);
+            patch_vertices.push(PatchVertex {
+                id,
+                name: name.0.clone(),
+                kind: kind.name().to_string(),
+                position: transform.translation.xy().into(),
+                parameters: kind.inputs().iter()
+                    .zip(parameters.iter())
+                    .map(|(input, value)| (input.to_string(), *value))
+                    .collect(),
+            });
+        }
+
+        let mut edges: Vec<PatchEdge> = graph.directed_edges()
+            .filter_map(|(_, edge)| Some(PatchEdge {
+                source: *ids.get(&edge.source)?,
+                output: edge.output,
+                sink: *ids.get(&edge.sink)?,
+                input: edge.input.clone(),
+            }))
+            .collect();
+        edges.sort_by(|a, b| (a.source, a.output, a.sink, &a.input).cmp(&(b.source, b.output, b.sink, &b.input)));
+
+        Patch {
+            version: PATCH_VERSION,
+            vertices: patch_vertices,
+            edges,
+        }
+    }
+
+    /// Spawns the patch's vertices moved by `offset`, and edge builders between them.
+    ///
+    /// Nothing is spawned unless the whole patch is valid.
+    pub fn spawn(&self, commands: &mut Commands, registry: &NodeRegistry, offset: Vec2) -> Result<Vec<Entity>, PatchError> {
+        let nodes = self.validate(registry)?;
+
+        let mut entities = HashMap::new();
+        for (vertex, (kind, parameters)) in self.vertices.iter().zip(nodes) {
+            let position = Vec2::from(vertex.position) + offset;
+            let entity = commands.spawn((
+                VertexBundle::new(position.extend(1.0), vertex.name.clone(), VERTEX_HALF_EXTEND),
+                kind,
+                parameters,
+            )).id();
+            entities.insert(vertex.id, entity);
+        }
+        for edge in self.edges.iter() {
+            commands.spawn(EdgeBuilder {
+                u: entities[&edge.source],
+                v: entities[&edge.sink],
+                output: edge.output,
+                input: edge.input.clone(),
+            });
+        }
+        Ok(self.vertices.iter().map(|vertex| entities[&vertex.id]).collect())
+    }
+
+    /// Checks that vertex ids are unique, every vertex's node kind is registered and every edge connects
+    /// existing ports, returning each vertex's node in order.
+    pub fn validate(&self, registry: &NodeRegistry) -> Result<Vec<(NodeKind, NodeParameters)>, PatchError> {
+        let mut ids = HashSet::new();
+        if let Some(vertex) = self.vertices.iter().find(|vertex| !ids.insert(vertex.id)) {
+            return Err(PatchError::DuplicateVertex(vertex.id));
+        }
+        let nodes = self.vertices.iter()
+            .map(|vertex| vertex.node(registry))
+            .collect::<Result<Vec<_>, _>>()?;
+        let kind_of = |id: usize| self.vertices.iter()
+            .position(|vertex| vertex.id == id)
+            .map(|index| &nodes[index].0)
+            .ok_or(PatchError::UnknownVertex(id));
+
+        for edge in self.edges.iter() {
+            let (source, sink) = (kind_of(edge.source)?, kind_of(edge.sink)?);
+            if edge.output >= source.num_outputs() {
+                return Err(PatchError::UnknownPort { vertex: edge.source, port: format!("output {}", edge.output) });
+            }
+            if !sink.ports().contains(&edge.input.as_str()) {
+                return Err(PatchError::UnknownPort { vertex: edge.sink, port: format!("input {}", edge.input) });
+            }
+        }
+        Ok(nodes)
+    }
+
+    pub fn to_json(&self) -> Result<String, PatchError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Parses a patch, migrating it from older versions of the format.
+    pub fn from_json(json: &str) -> Result<Self, PatchError> {
+        let value = migrate(serde_json::from_str(json)?, MIGRATIONS)?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), PatchError> {
+        Ok(fs::write(path, self.to_json()?)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PatchError> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+}
+
+// Upgrades a patch to the version after the last of `migrations`
+fn migrate(mut value: Value, migrations: &[Migration]) -> Result<Value, PatchError> {
+    let latest = migrations.len() as u32 + 1;
+    let version = value.get("version")
+        .and_then(Value::as_u64)
+        .ok_or(PatchError::MissingVersion)?;
+    let version = u32::try_from(version)
+        .ok()
+        .filter(|v| (1..=latest).contains(v))
+        .ok_or(PatchError::UnsupportedVersion(version))?;
+    for migration in &migrations[(version - 1) as usize..] {
+        migration(&mut value)?;
+    }
+    value["version"] = latest.into();
+    Ok(value)
+}
+
+pub struct SavePatch(pub PathBuf);
+
+pub struct LoadPatch(pub PathBuf);
+
+#[derive(Resource)]
+pub struct PatchFile {
+    pub path: String,
+    pub status: Option<String>,
+}
+
+impl Default for PatchFile {
+    fn default() -> Self {
+        PatchFile {
+            path: "patch.json".to_string(),
+            status: None,
+        }
+    }
+}
+
+fn save_patch(
+    mut events: EventReader<SavePatch>,
+    mut patch_file: ResMut<PatchFile>,
+    graph: Res<Graph>,
+    vertices: PatchVertexQuery,
+) {
+    for SavePatch(path) in events.iter() {
+        let result = Patch::collect(&graph, &vertices).save(path);
+        patch_file.status = Some(match result {
+            Ok(()) => format!("Saved {}", path.display()),
+            Err(e) => format!("Could not save {}: {e}", path.display()),
+        });
+    }
+}
+
+fn load_patch(
+    mut commands: Commands,
+    mut events: EventReader<LoadPatch>,
+    mut patch_file: ResMut<PatchFile>,
+    registry: Res<NodeRegistry>,
+    vertices: Query<Entity, With<Vertex>>,
+) {
+    for LoadPatch(path) in events.iter() {
+        let patch = match Patch::load(path) {
+            Ok(patch) => patch,
+            Err(e) => {
+                patch_file.status = Some(format!("Could not load {}: {e}", path.display()));
+                continue;
+            }
+        };
+        // Keep the current graph if the patch can't be spawned
+        if let Err(e) = patch.validate(&registry) {
+            patch_file.status = Some(format!("Could not load {}: {e}", path.display()));
+            continue;
+        }
+        // Edges are despawned along with their vertices
+        for entity in vertices.iter() {
+            commands.entity(entity).despawn_recursive();
+        }
+        commands.remove_resource::<GraphSelection>();
+        patch_file.status = Some(match patch.spawn(&mut commands, &registry, Vec2::ZERO) {
+            Ok(_) => format!("Loaded {}", path.display()),
+            Err(e) => format!("Could not load {}: {e}", path.display()),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_patch() -> Patch {
+        Patch {
+            version: PATCH_VERSION,
+            vertices: vec![
+                PatchVertex {
+                    id: 0,
+                    name: "osc".to_string(),
+                    kind: "Oscillator".to_string(),
+                    position: (10.0, -20.0),
+                    parameters: BTreeMap::from([("freq".to_string(), 220.0)]),
+                },
+                PatchVertex {
+                    id: 1,
+                    name: "out".to_string(),
+                    kind: "Output".to_string(),
+                    position: (200.0, 0.0),
+                    parameters: BTreeMap::new(),
+                },
+            ],
+            edges: vec![PatchEdge { source: 0, output: 0, sink: 1, input: "left".to_string() }],
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let patch = example_patch();
+        assert_eq!(Patch::from_json(&patch.to_json().unwrap()).unwrap(), patch);
+    }
+
+    #[test]
+    fn migrates_older_versions() {
+        // A pretend version 1 that called vertices "nodes"
+        fn rename_nodes(value: &mut Value) -> Result<(), PatchError> {
+            let nodes = value.as_object_mut().unwrap().remove("nodes").ok_or(PatchError::MissingVersion)?;
+            value["vertices"] = nodes;
+            Ok(())
+        }
+        let old = r#"{ "version": 1, "nodes": [], "edges": [] }"#;
+        let value = migrate(serde_json::from_str(old).unwrap(), &[rename_nodes as Migration]).unwrap();
+        assert_eq!(value["version"], 2);
+        assert_eq!(value["vertices"], Value::Array(Vec::new()));
+        assert!(value.get("nodes").is_none());
+    }
+
+    #[test]
+    fn rejects_future_and_missing_versions() {
+        let future = format!(r#"{{ "version": {}, "vertices": [], "edges": [] }}"#, PATCH_VERSION + 1);
+        assert!(matches!(Patch::from_json(&future), Err(PatchError::UnsupportedVersion(v)) if v == PATCH_VERSION as u64 + 1));
+        // Too big for the version to be read as a u32
+        assert!(matches!(Patch::from_json(r#"{ "version": 4294967297, "vertices": [], "edges": [] }"#), Err(PatchError::UnsupportedVersion(4294967297))));
+        assert!(matches!(Patch::from_json(r#"{ "version": 0, "vertices": [], "edges": [] }"#), Err(PatchError::UnsupportedVersion(0))));
+        assert!(matches!(Patch::from_json(r#"{ "vertices": [], "edges": [] }"#), Err(PatchError::MissingVersion)));
+    }
+
+    #[test]
+    fn validate_rejects_unknown_kinds_vertices_and_ports() {
+        let registry = NodeRegistry::default();
+        assert!(example_patch().validate(&registry).is_ok());
+
+        let mut patch = example_patch();
+        patch.vertices[0].kind = "Theremin".to_string();
+        assert!(matches!(patch.validate(&registry), Err(PatchError::UnknownNodeKind(_))));
+
+        let mut patch = example_patch();
+        patch.vertices[1].id = 0;
+        assert!(matches!(patch.validate(&registry), Err(PatchError::DuplicateVertex(0))));
+
+        let mut patch = example_patch();
+        patch.edges[0].sink = 7;
+        assert!(matches!(patch.validate(&registry), Err(PatchError::UnknownVertex(7))));
+
+        let mut patch = example_patch();
+        patch.edges[0].input = "middle".to_string();
+        assert!(matches!(patch.validate(&registry), Err(PatchError::UnknownPort { vertex: 1, .. })));
+
+        let mut patch = example_patch();
+        patch.edges[0].output = 3;
+        assert!(matches!(patch.validate(&registry), Err(PatchError::UnknownPort { vertex: 0, .. })));
+    }
+}
@@ -0,0 +1,411 @@
+use std::f32::consts::PI;
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_egui::{EguiContexts, egui::{self, Id}};
+
+use crate::{
+    AppSet, AudioCommands, Mode,
+    graph::{Vertex, VertexName},
+    node::{NodeKind, NodeParameters, ParameterSpec, AudioNodes, AudioOperation, AudioOperations, set_input},
+};
+
+const PERFORMANCE_PANEL_ID: &str = "performance_panel";
+
+// Time constant of the smoothing, in seconds
+const SMOOTHING_TIME: f32 = 0.03;
+// Smoothing stops once within this fraction of the parameter's range from the target
+const SMOOTHING_TOLERANCE: f32 = 0.001;
+
+const KNOB_SIZE: f32 = 48.0;
+// Fraction of the knob's range per point dragged
+const KNOB_SENSITIVITY: f32 = 0.005;
+const XY_PAD_SIZE: f32 = 160.0;
+
+pub struct PerformPlugin;
+
+impl Plugin for PerformPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PerformanceControls>()
+            .init_resource::<ParameterSmoothers>()
+            .add_system(performance_panel
+                .in_set(AppSet::Ui)
+                .run_if(state_exists_and_equals(Mode::Interact))
+            )
+            .add_systems((
+                prune_controls,
+                smooth_parameters,
+            )
+                .chain()
+                .after(AppSet::Ui)
+                .before(AppSet::GraphManagement)
+            );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlWidget {
+    Knob,
+    Slider,
+    Toggle,
+    // Drives two parameters at once
+    XyPad,
+    // On while held
+    Button,
+}
+
+impl ControlWidget {
+    pub const ALL: [ControlWidget; 5] = [
+        ControlWidget::Knob,
+        ControlWidget::Slider,
+        ControlWidget::Toggle,
+        ControlWidget::XyPad,
+        ControlWidget::Button,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlWidget::Knob => "Knob",
+            ControlWidget::Slider => "Slider",
+            ControlWidget::Toggle => "Toggle",
+            ControlWidget::XyPad => "XY pad",
+            ControlWidget::Button => "Button",
+        }
+    }
+
+    pub fn num_bindings(&self) -> usize {
+        match self {
+            ControlWidget::XyPad => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// An input of a vertex's node that a control drives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ParameterBinding {
+    pub vertex: Entity,
+    pub input: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Control {
+    pub label: String,
+    pub widget: ControlWidget,
+    /// One per `ControlWidget::num_bindings`, x before y for an XY pad.
+    pub bindings: Vec<ParameterBinding>,
+    held: bool,
+}
+
+impl Control {
+    pub fn new(label: String, widget: ControlWidget, bindings: Vec<ParameterBinding>) -> Self {
+        Control { label, widget, bindings, held: false }
+    }
+}
+
+/// The controls shown in `Mode::Interact`, in panel order.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct PerformanceControls(pub Vec<Control>);
+
+struct Smoother {
+    current: f32,
+    target: f32,
+    tolerance: f32,
+}
+
+/// Parameters still gliding towards the value a control last set.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct ParameterSmoothers(HashMap<ParameterBinding, Smoother>);
+
+impl ParameterSmoothers {
+    /// Glides `binding` from `current` to `target`, or redirects a glide already under way.
+    pub(crate) fn glide(&mut self, binding: ParameterBinding, current: f32, target: f32, spec: &ParameterSpec) {
+        let tolerance = SMOOTHING_TOLERANCE * (spec.range.end() - spec.range.start());
+        self.entry(binding)
+            .or_insert(Smoother { current, target, tolerance })
+            .target = target;
+    }
+
+    /// Where `binding` is gliding to, if it's still moving.
+    pub(crate) fn target(&self, binding: ParameterBinding) -> Option<f32> {
+        self.get(&binding).map(|smoother| smoother.target)
+    }
+}
+
+// Toggles and buttons switch between these, so a gate rests at 0 when the range allows it
+fn off_value(spec: &ParameterSpec) -> f32 {
+    if spec.range.contains(&0.0) { 0.0 } else { *spec.range.start() }
+}
+
+fn on_value(spec: &ParameterSpec) -> f32 {
+    *spec.range.end()
+}
+
+fn to_fraction(spec: &ParameterSpec, value: f32) -> f32 {
+    let (min, max) = (*spec.range.start(), *spec.range.end());
+    let fraction = if spec.logarithmic && min > 0.0 {
+        (value / min).ln() / (max / min).ln()
+    }
+    else {
+        (value - min) / (max - min)
+    };
+    if fraction.is_finite() { fraction.clamp(0.0, 1.0) } else { 0.0 }
+}
+
+pub(crate) fn from_fraction(spec: &ParameterSpec, fraction: f32) -> f32 {
+    let (min, max) = (*spec.range.start(), *spec.range.end());
+    if spec.logarithmic && min > 0.0 {
+        min * (max / min).powf(fraction)
+    }
+    else {
+        min + (max - min) * fraction
+    }
+}
+
+fn knob(ui: &mut egui::Ui, fraction: &mut f32) -> egui::Response {
+    let (rect, mut response) = ui.allocate_exact_size(egui::vec2(KNOB_SIZE, KNOB_SIZE), egui::Sense::drag());
+    if response.dragged() {
+        let delta = response.drag_delta();
+        let new_fraction = (*fraction + (delta.x - delta.y) * KNOB_SENSITIVITY).clamp(0.0, 1.0);
+        if new_fraction != *fraction {
+            *fraction = new_fraction;
+            response.mark_changed();
+        }
+    }
+
+    if ui.is_rect_visible(rect) {
+        let visuals = ui.style().interact(&response);
+        let centre = rect.center();
+        let radius = rect.width() / 2.0 - 2.0;
+        ui.painter().circle(centre, radius, visuals.bg_fill, visuals.fg_stroke);
+        // Sweeps 270 degrees clockwise from the bottom left; egui's y axis points down
+        let angle = (0.75 + 1.5 * *fraction) * PI;
+        let tip = centre + 0.8 * radius * egui::vec2(angle.cos(), angle.sin());
+        ui.painter().line_segment([centre, tip], visuals.fg_stroke);
+    }
+    response
+}
+
+fn xy_pad(ui: &mut egui::Ui, x: &mut f32, y: &mut f32) -> egui::Response {
+    let (rect, mut response) = ui.allocate_exact_size(egui::vec2(XY_PAD_SIZE, XY_PAD_SIZE), egui::Sense::click_and_drag());
+    if response.dragged() || response.clicked() {
+        if let Some(pos) = response.interact_pointer_pos() {
+            *x = ((pos.x - rect.left()) / rect.width()).clamp(0.0, 1.0);
+            *y = ((rect.bottom() - pos.y) / rect.height()).clamp(0.0, 1.0);
+            response.mark_changed();
+        }
+    }
+
+    if ui.is_rect_visible(rect) {
+        let visuals = ui.style().interact(&response);
+        ui.painter().rect(rect, 2.0, ui.visuals().extreme_bg_color, visuals.bg_stroke);
+        let point = egui::pos2(rect.left() + *x * rect.width(), rect.bottom() - *y * rect.height());
+        ui.painter().circle_filled(point, 5.0, visuals.fg_stroke.color);
+    }
+    response
+}
+
+/// Draws `control` showing `values`, returning the new values if it was moved, and whether they should
+/// glide there.
+fn control_ui(ui: &mut egui::Ui, control: &mut Control, values: &[f32], specs: &[ParameterSpec]) -> Option<(Vec<f32>, bool)> {
+    let spec = &specs[0];
+    match control.widget {
+        ControlWidget::Knob => {
+            let mut fraction = to_fraction(spec, values[0]);
+            let changed = ui.horizontal(|ui| {
+                let changed = knob(ui, &mut fraction).changed();
+                ui.label(format!("{:.2}{}", from_fraction(spec, fraction), spec.unit));
+                changed
+            }).inner;
+            changed.then(|| (vec![from_fraction(spec, fraction)], true))
+        }
+        ControlWidget::Slider => {
+            let mut value = values[0];
+            let slider = egui::Slider::new(&mut value, spec.range.clone())
+                .logarithmic(spec.logarithmic)
+                .suffix(spec.unit);
+            ui.add(slider).changed().then(|| (vec![value], true))
+        }
+        ControlWidget::Toggle => {
+            let mut on = values[0] > off_value(spec);
+            let text = if on { "On" } else { "Off" };
+            ui.toggle_value(&mut on, text).changed()
+                .then(|| (vec![if on { on_value(spec) } else { off_value(spec) }], false))
+        }
+        ControlWidget::XyPad => {
+            let (mut x, mut y) = (to_fraction(spec, values[0]), to_fraction(&specs[1], values[1]));
+            xy_pad(ui, &mut x, &mut y).changed()
+                .then(|| (vec![from_fraction(spec, x), from_fraction(&specs[1], y)], true))
+        }
+        ControlWidget::Button => {
+            let response = ui.add(egui::Button::new(control.label.as_str()).sense(egui::Sense::click_and_drag()));
+            let down = response.is_pointer_button_down_on();
+            if down == control.held { return None; }
+            control.held = down;
+            Some((vec![if down { on_value(spec) } else { off_value(spec) }], false))
+        }
+    }
+}
+
+#[derive(Default)]
+struct ControlDraft {
+    widget: Option<ControlWidget>,
+    vertex: Option<Entity>,
+    inputs: [usize; 2],
+}
+
+pub(crate) fn performance_panel(
+    mut contexts: EguiContexts,
+    mut controls: ResMut<PerformanceControls>,
+    mut smoothers: ResMut<ParameterSmoothers>,
+    mut vertices: Query<(Entity, &VertexName, &NodeKind, &mut NodeParameters), With<Vertex>>,
+    mut draft: Local<ControlDraft>,
+) {
+    let mut changes = Vec::new();
+    let mut removed = None;
+
+    egui::SidePanel::right(Id::new(PERFORMANCE_PANEL_ID)).show(contexts.ctx_mut(), |ui| {
+        ui.heading("Performance");
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            for (index, control) in controls.iter_mut().enumerate() {
+                // A gliding parameter shows where it's headed rather than where it is
+                let bound: Option<(Vec<f32>, Vec<ParameterSpec>)> = control.bindings.iter()
+                    .map(|binding| {
+                        let (_, _, kind, parameters) = vertices.get(binding.vertex).ok()?;
+                        let value = smoothers.target(*binding)
+                            .or_else(|| parameters.get(binding.input).copied())?;
+                        Some((value, kind.parameter_spec(binding.input)))
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .map(|bound| bound.into_iter().unzip());
+                let Some((values, specs)) = bound else { continue };
+
+                ui.group(|ui| {
+                    ui.horizontal(|ui| {
+                        ui.label(control.label.as_str());
+                        if ui.small_button("x").clicked() {
+                            removed = Some(index);
+                        }
+                    });
+                    if let Some((new_values, smooth)) = control_ui(ui, control, &values, &specs) {
+                        changes.extend(control.bindings.iter().copied().zip(new_values).map(|(binding, value)| (binding, value, smooth)));
+                    }
+                });
+            }
+
+            ui.separator();
+            ui.label("Add control");
+            add_control_ui(ui, &mut draft, &mut controls, &vertices);
+        });
+    });
+
+    if let Some(index) = removed {
+        controls.remove(index);
+    }
+    for (binding, value, smooth) in changes {
+        let Ok((_, _, kind, mut parameters)) = vertices.get_mut(binding.vertex) else { continue };
+        let Some(current) = parameters.get(binding.input).copied() else { continue };
+        if smooth {
+            smoothers.glide(binding, current, value, &kind.parameter_spec(binding.input));
+        }
+        else {
+            smoothers.remove(&binding);
+            parameters[binding.input] = value;
+        }
+    }
+}
+
+fn add_control_ui(
+    ui: &mut egui::Ui,
+    draft: &mut ControlDraft,
+    controls: &mut PerformanceControls,
+    vertices: &Query<(Entity, &VertexName, &NodeKind, &mut NodeParameters), With<Vertex>>,
+) {
+    let widget = draft.widget.get_or_insert(ControlWidget::Knob);
+    egui::ComboBox::from_label("Widget")
+        .selected_text(widget.name())
+        .show_ui(ui, |ui| {
+            for option in ControlWidget::ALL {
+                ui.selectable_value(widget, option, option.name());
+            }
+        });
+    let widget = *widget;
+
+    let vertex = draft.vertex.and_then(|entity| vertices.get(entity).ok());
+    egui::ComboBox::from_label("Node")
+        .selected_text(vertex.map_or("", |(_, name, ..)| name.0.as_str()))
+        .show_ui(ui, |ui| {
+            for (entity, name, kind, _) in vertices.iter() {
+                if kind.inputs().is_empty() { continue; }
+                if ui.selectable_label(draft.vertex == Some(entity), name.0.as_str()).clicked() {
+                    draft.vertex = Some(entity);
+                    draft.inputs = [0, 0];
+                }
+            }
+        });
+    let Some((entity, name, kind, _)) = vertex else { return };
+
+    let inputs = kind.inputs();
+    for (axis, input) in draft.inputs.iter_mut().enumerate().take(widget.num_bindings()) {
+        let label = if widget == ControlWidget::XyPad { ["X parameter", "Y parameter"][axis] } else { "Parameter" };
+        egui::ComboBox::from_label(label)
+            .selected_text(inputs.get(*input).copied().unwrap_or(""))
+            .show_ui(ui, |ui| {
+                for (index, name) in inputs.iter().enumerate() {
+                    ui.selectable_value(input, index, *name);
+                }
+            });
+    }
+
+    if ui.button("Add").clicked() {
+        let bindings: Vec<ParameterBinding> = draft.inputs[..widget.num_bindings()].iter()
+            .filter(|input| **input < inputs.len())
+            .map(|input| ParameterBinding { vertex: entity, input: *input })
+            .collect();
+        if bindings.len() == widget.num_bindings() {
+            let parameters: Vec<&str> = bindings.iter().map(|binding| inputs[binding.input]).collect();
+            let label = format!("{} {}", name.0, parameters.join("/"));
+            controls.push(Control::new(label, widget, bindings));
+        }
+    }
+}
+
+// Drops controls whose vertex is gone or no longer has the same inputs
+fn prune_controls(
+    mut controls: ResMut<PerformanceControls>,
+    mut smoothers: ResMut<ParameterSmoothers>,
+    mut removed_vertices: RemovedComponents<Vertex>,
+    changed_kinds: Query<Entity, (With<Vertex>, Changed<NodeKind>)>,
+) {
+    let stale: Vec<Entity> = removed_vertices.iter().chain(changed_kinds.iter()).collect();
+    if stale.is_empty() { return; }
+
+    controls.retain(|control| control.bindings.iter().all(|binding| !stale.contains(&binding.vertex)));
+    smoothers.retain(|binding, _| !stale.contains(&binding.vertex));
+}
+
+fn smooth_parameters(
+    time: Res<Time>,
+    mut audio_commands: Option<ResMut<AudioCommands>>,
+    audio_nodes: Res<AudioNodes>,
+    mut audio_operations: ResMut<AudioOperations>,
+    mut smoothers: ResMut<ParameterSmoothers>,
+    mut vertices: Query<&mut NodeParameters, With<Vertex>>,
+) {
+    let follow = 1.0 - (-time.delta_seconds() / SMOOTHING_TIME).exp();
+    smoothers.retain(|binding, smoother| {
+        let Ok(mut parameters) = vertices.get_mut(binding.vertex) else { return false };
+        if binding.input >= parameters.len() { return false; }
+
+        smoother.current += (smoother.target - smoother.current) * follow;
+        if (smoother.target - smoother.current).abs() <= smoother.tolerance {
+            // Settling sends the final value through the usual parameter change, which also makes it undoable
+            parameters[binding.input] = smoother.target;
+            return false;
+        }
+        if let (Some(audio_commands), Some(address)) = (audio_commands.as_mut(), audio_nodes.get(&binding.vertex)) {
+            set_input(audio_commands, address, binding.input, smoother.current);
+            audio_operations.record(AudioOperation::SetInput, binding.vertex);
+        }
+        true
+    });
+}
@@ -0,0 +1,335 @@
+use std::{fmt, path::{Path, PathBuf}, sync::{Mutex, mpsc}};
+
+use bevy::{prelude::*, utils::HashMap, tasks::AsyncComputeTaskPool};
+use hound::{WavSpec, WavWriter, SampleFormat};
+use knyst::{
+    audio_backend::AudioBackendError,
+    prelude::{AudioBackend, Graph as AudioGraph, GraphSettings, RunGraphSettings},
+    KnystError,
+};
+
+use crate::{
+    AppSet, OfflineBackend,
+    graph::Graph,
+    node::{NodeRegistry, MIN_OUTPUT_CHANNELS, port_connection},
+    patch::{Patch, PatchEdge, PatchError, PatchVertexQuery},
+    ui::Notifications,
+};
+
+const RENDER_BLOCK_SIZE: usize = 64;
+
+pub struct OfflineRenderPlugin;
+
+impl Plugin for OfflineRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RenderFile>()
+            .init_resource::<RenderJobs>()
+            .add_event::<RenderWav>()
+            .add_systems((
+                render_wav,
+                finish_renders,
+            )
+                .chain()
+                .after(AppSet::Ui)
+            );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitDepth {
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl BitDepth {
+    pub const ALL: [BitDepth; 3] = [BitDepth::Int16, BitDepth::Int24, BitDepth::Float32];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BitDepth::Int16 => "16-bit",
+            BitDepth::Int24 => "24-bit",
+            BitDepth::Float32 => "32-bit float",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderOptions {
+    // In seconds
+    pub duration: f32,
+    pub sample_rate: usize,
+    pub num_channels: usize,
+    pub bit_depth: BitDepth,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            duration: 5.0,
+            sample_rate: 44100,
+            num_channels: 2,
+            bit_depth: BitDepth::Int16,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RenderError {
+    Patch(PatchError),
+    Audio(AudioBackendError),
+    TooFewChannels(usize),
+    /// Errors the audio engine reported while building the patch's graph.
+    Engine(Vec<String>),
+    UnresolvedEdge(PatchEdge),
+    Wav(hound::Error),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Patch(e) => write!(f, "{e}"),
+            RenderError::Audio(e) => write!(f, "audio error: {e:?}"),
+            RenderError::TooFewChannels(n) => write!(f, "can't render {n} channels, at least {MIN_OUTPUT_CHANNELS} are needed"),
+            RenderError::Engine(errors) => write!(f, "audio engine error: {}", errors.join("; ")),
+            RenderError::UnresolvedEdge(edge) => write!(
+                f, "could not connect output {} of vertex {} to input {} of vertex {}",
+                edge.output, edge.source, edge.input, edge.sink
+            ),
+            RenderError::Wav(e) => write!(f, "could not write wav: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+impl From<PatchError> for RenderError {
+    fn from(e: PatchError) -> Self {
+        RenderError::Patch(e)
+    }
+}
+
+impl From<AudioBackendError> for RenderError {
+    fn from(e: AudioBackendError) -> Self {
+        RenderError::Audio(e)
+    }
+}
+
+impl From<hound::Error> for RenderError {
+    fn from(e: hound::Error) -> Self {
+        RenderError::Wav(e)
+    }
+}
+
+/// Renders `options.duration` seconds of `patch` in a fresh audio graph, one `Vec` per output channel.
+pub fn render_patch(patch: &Patch, registry: &NodeRegistry, options: &RenderOptions) -> Result<Vec<Vec<f32>>, RenderError> {
+    if options.num_channels < MIN_OUTPUT_CHANNELS {
+        return Err(RenderError::TooFewChannels(options.num_channels));
+    }
+    let nodes: HashMap<_, _> = patch.vertices.iter()
+        .map(|vertex| vertex.id)
+        .zip(patch.validate(registry)?)
+        .collect();
+
+    let mut backend = OfflineBackend::new(options.sample_rate, RENDER_BLOCK_SIZE, options.num_channels, false);
+    let graph = AudioGraph::new(GraphSettings {
+        block_size: RENDER_BLOCK_SIZE,
+        sample_rate: options.sample_rate as f32,
+        num_outputs: options.num_channels,
+        ..Default::default()
+    });
+    let resources = knyst::Resources::new(knyst::ResourcesSettings::default());
+    let (error_sender, error_receiver) = mpsc::channel();
+    let mut controller = backend.start_processing_return_controller(
+        graph,
+        resources,
+        RunGraphSettings::default(),
+        move |e: KnystError| {
+            let _ = error_sender.send(e.to_string());
+        },
+    )?;
+    let mut commands = controller.get_knyst_commands();
+
+    let addresses: HashMap<_, _> = nodes.iter()
+        .map(|(id, (kind, parameters))| (*id, kind.push(&mut commands, parameters)))
+        .collect();
+    for edge in patch.edges.iter() {
+        let source = (&nodes[&edge.source].0, &addresses[&edge.source]);
+        let sink = (&nodes[&edge.sink].0, &addresses[&edge.sink]);
+        let Some(connection) = port_connection(source, edge.output, sink, &edge.input) else {
+            backend.stop()?;
+            return Err(RenderError::UnresolvedEdge(edge.clone()));
+        };
+        commands.connect(connection);
+    }
+    while !controller.run(300) {}
+    // Rendering a graph that failed to build would only give silence
+    let errors: Vec<String> = error_receiver.try_iter().collect();
+    if !errors.is_empty() {
+        backend.stop()?;
+        return Err(RenderError::Engine(errors));
+    }
+
+    let num_samples = (options.duration * options.sample_rate as f32).round() as usize;
+    let mut output = vec![Vec::with_capacity(num_samples); options.num_channels];
+    for _ in 0..num_samples.div_ceil(RENDER_BLOCK_SIZE) {
+        let Some(block) = backend.process_block() else { break };
+        for (channel, samples) in output.iter_mut().zip(block) {
+            channel.extend(samples);
+        }
+    }
+    for channel in output.iter_mut() {
+        channel.truncate(num_samples);
+    }
+    backend.stop()?;
+    Ok(output)
+}
+
+pub fn write_wav(path: impl AsRef<Path>, channels: &[Vec<f32>], sample_rate: usize, bit_depth: BitDepth) -> Result<(), RenderError> {
+    let spec = WavSpec {
+        channels: channels.len() as u16,
+        sample_rate: sample_rate as u32,
+        bits_per_sample: match bit_depth {
+            BitDepth::Int16 => 16,
+            BitDepth::Int24 => 24,
+            BitDepth::Float32 => 32,
+        },
+        sample_format: match bit_depth {
+            BitDepth::Int16 | BitDepth::Int24 => SampleFormat::Int,
+            BitDepth::Float32 => SampleFormat::Float,
+        },
+    };
+    let mut writer = WavWriter::create(path, spec)?;
+    let num_samples = channels.first().map_or(0, Vec::len);
+    for i in 0..num_samples {
+        for channel in channels {
+            let sample = channel[i];
+            match bit_depth {
+                BitDepth::Int16 => writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?,
+                BitDepth::Int24 => writer.write_sample((sample.clamp(-1.0, 1.0) * 8_388_607.0) as i32)?,
+                BitDepth::Float32 => writer.write_sample(sample)?,
+            }
+        }
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+pub fn render_patch_to_wav(patch: &Patch, registry: &NodeRegistry, options: &RenderOptions, path: impl AsRef<Path>) -> Result<(), RenderError> {
+    let output = render_patch(patch, registry, options)?;
+    write_wav(path, &output, options.sample_rate, options.bit_depth)
+}
+
+pub fn render_patch_file_to_wav(patch_path: impl AsRef<Path>, registry: &NodeRegistry, options: &RenderOptions, path: impl AsRef<Path>) -> Result<(), RenderError> {
+    render_patch_to_wav(&Patch::load(patch_path)?, registry, options, path)
+}
+
+/// Renders the patch currently in the editor.
+pub struct RenderWav {
+    pub path: PathBuf,
+    pub options: RenderOptions,
+}
+
+#[derive(Resource)]
+pub struct RenderFile {
+    pub path: String,
+    pub options: RenderOptions,
+    pub status: Option<String>,
+}
+
+impl Default for RenderFile {
+    fn default() -> Self {
+        RenderFile {
+            path: "patch.wav".to_string(),
+            options: RenderOptions::default(),
+            status: None,
+        }
+    }
+}
+
+// Renders run on the async compute pool so long ones don't freeze the app, and report back through here
+#[derive(Resource)]
+struct RenderJobs {
+    sender: Mutex<mpsc::Sender<(PathBuf, Result<(), RenderError>)>>,
+    receiver: Mutex<mpsc::Receiver<(PathBuf, Result<(), RenderError>)>>,
+}
+
+impl Default for RenderJobs {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        RenderJobs { sender: Mutex::new(sender), receiver: Mutex::new(receiver) }
+    }
+}
+
+fn render_wav(
+    mut events: EventReader<RenderWav>,
+    mut render_file: ResMut<RenderFile>,
+    jobs: Res<RenderJobs>,
+    registry: Res<NodeRegistry>,
+    graph: Res<Graph>,
+    vertices: PatchVertexQuery,
+) {
+    for RenderWav { path, options } in events.iter() {
+        let patch = Patch::collect(&graph, &vertices);
+        let (path, options, registry) = (path.clone(), options.clone(), registry.clone());
+        let sender = jobs.sender.lock().unwrap().clone();
+        render_file.status = Some(format!("Rendering {}...", path.display()));
+        AsyncComputeTaskPool::get().spawn(async move {
+            let result = render_patch_to_wav(&patch, &registry, &options, &path);
+            let _ = sender.send((path, result));
+        }).detach();
+    }
+}
+
+fn finish_renders(
+    jobs: Res<RenderJobs>,
+    mut render_file: ResMut<RenderFile>,
+    mut notifications: ResMut<Notifications>,
+) {
+    for (path, result) in jobs.receiver.lock().unwrap().try_iter() {
+        let status = match result {
+            Ok(()) => format!("Rendered {}", path.display()),
+            Err(e) => format!("Could not render {}: {e}", path.display()),
+        };
+        notifications.push(status.clone());
+        render_file.status = Some(status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::patch::{PatchVertex, PATCH_VERSION};
+
+    fn sine_patch() -> Patch {
+        let vertex = |id: usize, kind: &str| PatchVertex {
+            id,
+            name: kind.to_lowercase(),
+            kind: kind.to_string(),
+            position: (0.0, 0.0),
+            parameters: BTreeMap::new(),
+        };
+        Patch {
+            version: PATCH_VERSION,
+            vertices: vec![vertex(0, "Oscillator"), vertex(1, "Output")],
+            edges: vec![PatchEdge { source: 0, output: 0, sink: 1, input: "left".to_string() }],
+        }
+    }
+
+    #[test]
+    fn renders_stereo() {
+        let options = RenderOptions { duration: 0.1, ..Default::default() };
+        let output = render_patch(&sine_patch(), &NodeRegistry::default(), &options).unwrap();
+        assert_eq!(output.len(), 2);
+        assert!(output.iter().all(|channel| channel.len() == 4410));
+    }
+
+    #[test]
+    fn rejects_mono() {
+        let options = RenderOptions { duration: 0.1, num_channels: 1, ..Default::default() };
+        let result = render_patch(&sine_patch(), &NodeRegistry::default(), &options);
+        assert!(matches!(result, Err(RenderError::TooFewChannels(1))));
+    }
+}
@@ -0,0 +1,361 @@
+use std::sync::{Arc, atomic::{AtomicU32, AtomicUsize, Ordering}};
+
+use bevy::{prelude::*, utils::HashMap, window::PrimaryWindow};
+use bevy_egui::{EguiContexts, egui::{self, Id, plot::{Plot, Line, PlotPoints}}};
+use knyst::{prelude::*, graph::NodeAddress, controller::KnystCommands, Resources};
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+
+use crate::{
+    AppSet, AudioCommands, AudioRestarted, AudioStatus,
+    camera::PrimaryCamera,
+    graph::{GraphSelection, Vertex, VertexName},
+    node::{NodeKind, NodeRegistry, NodeType, RegisterNodeType, AudioNodes, AudioOperation, AudioOperations},
+};
+
+// Samples kept by each scope, enough for a spectrum plus room to find a trigger
+const SCOPE_BUFFER_SIZE: usize = 4096;
+// Samples drawn by the oscilloscope
+const OSCILLOSCOPE_WINDOW: usize = 1024;
+const FFT_SIZE: usize = 2048;
+const SPECTRUM_FLOOR_DB: f64 = -100.0;
+const DEFAULT_SAMPLE_RATE: usize = 44100;
+
+pub struct ScopePlugin;
+
+impl Plugin for ScopePlugin {
+    fn build(&self, app: &mut App) {
+        app.register_node_types(register_nodes)
+            .init_resource::<ScopeNodes>()
+            .init_resource::<SpectrumFft>()
+            .add_systems((
+                detach_scopes,
+                attach_scopes,
+            )
+                .chain()
+                .after(AppSet::GraphManagement)
+            )
+            .add_system(scope_windows.in_set(AppSet::Ui));
+    }
+}
+
+pub fn register_nodes(registry: &mut NodeRegistry) {
+    registry.register(OscilloscopeNode);
+    registry.register(SpectrumNode);
+}
+
+pub struct OscilloscopeNode;
+
+impl NodeType for OscilloscopeNode {
+    fn name(&self) -> &'static str { "Oscilloscope" }
+
+    fn category(&self) -> &'static str { "Analysis" }
+
+    fn inputs(&self) -> &'static [&'static str] { &["in"] }
+
+    fn outputs(&self) -> &'static [&'static str] { &[] }
+
+    fn default_inputs(&self) -> &'static [f32] { &[0.0] }
+
+    // Passes its input through to a hidden output, which the capture node taps
+    fn construct(&self, commands: &mut KnystCommands) -> NodeAddress {
+        commands.push(Bus(1), inputs!())
+    }
+}
+
+pub struct SpectrumNode;
+
+impl NodeType for SpectrumNode {
+    fn name(&self) -> &'static str { "Spectrum" }
+
+    fn category(&self) -> &'static str { "Analysis" }
+
+    fn inputs(&self) -> &'static [&'static str] { &["in"] }
+
+    fn outputs(&self) -> &'static [&'static str] { &[] }
+
+    fn default_inputs(&self) -> &'static [f32] { &[0.0] }
+
+    fn construct(&self, commands: &mut KnystCommands) -> NodeAddress {
+        commands.push(Bus(1), inputs!())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScopeKind {
+    Oscilloscope,
+    Spectrum,
+}
+
+impl ScopeKind {
+    fn of(kind: &NodeKind) -> Option<Self> {
+        if kind.name() == OscilloscopeNode.name() {
+            Some(ScopeKind::Oscilloscope)
+        }
+        else if kind.name() == SpectrumNode.name() {
+            Some(ScopeKind::Spectrum)
+        }
+        else {
+            None
+        }
+    }
+}
+
+/// A rolling window of samples, written by the audio thread and read by the main thread without locking.
+///
+/// A read can race with a write and see a few samples from the next block, which doesn't matter for drawing.
+pub struct ScopeBuffer {
+    samples: Vec<AtomicU32>,
+    // The total number of samples written
+    written: AtomicUsize,
+}
+
+impl ScopeBuffer {
+    fn new(len: usize) -> Self {
+        ScopeBuffer {
+            samples: (0..len).map(|_| AtomicU32::new(0)).collect(),
+            written: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, sample: f32) {
+        let written = self.written.load(Ordering::Relaxed);
+        self.samples[written % self.samples.len()].store(sample.to_bits(), Ordering::Relaxed);
+        self.written.store(written + 1, Ordering::Release);
+    }
+
+    /// The most recent samples, oldest first.
+    pub fn latest(&self) -> Vec<f32> {
+        let written = self.written.load(Ordering::Acquire);
+        let len = self.samples.len();
+        (written.saturating_sub(len)..written)
+            .map(|i| f32::from_bits(self.samples[i % len].load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+struct ScopeGen {
+    buffer: Arc<ScopeBuffer>,
+}
+
+impl Gen for ScopeGen {
+    fn process(&mut self, ctx: GenContext, _resources: &mut Resources) -> GenState {
+        for i in 0..ctx.block_size() {
+            self.buffer.push(ctx.inputs.read(0, i));
+        }
+        GenState::Continue
+    }
+
+    fn num_inputs(&self) -> usize { 1 }
+
+    fn num_outputs(&self) -> usize { 0 }
+
+    fn name(&self) -> &'static str { "ScopeCapture" }
+}
+
+/// The capture node of each scope vertex.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct ScopeNodes(HashMap<Entity, NodeAddress>);
+
+#[derive(Component)]
+pub struct ScopeCapture {
+    buffer: Arc<ScopeBuffer>,
+}
+
+/// How a scope vertex's window is shown.
+#[derive(Component)]
+pub struct ScopeView {
+    pub open: bool,
+    // The oscilloscope starts drawing where the signal rises through this level
+    pub trigger: f32,
+}
+
+impl Default for ScopeView {
+    fn default() -> Self {
+        ScopeView { open: true, trigger: 0.0 }
+    }
+}
+
+#[derive(Resource, Deref)]
+struct SpectrumFft(Arc<dyn Fft<f32>>);
+
+impl Default for SpectrumFft {
+    fn default() -> Self {
+        SpectrumFft(FftPlanner::new().plan_fft_forward(FFT_SIZE))
+    }
+}
+
+fn detach_scopes(
+    mut commands: Commands,
+    mut restarts: EventReader<AudioRestarted>,
+    audio_commands: Option<ResMut<AudioCommands>>,
+    mut audio_operations: ResMut<AudioOperations>,
+    mut scope_nodes: ResMut<ScopeNodes>,
+    mut removed_vertices: RemovedComponents<Vertex>,
+    changed_kinds: Query<Entity, Changed<NodeKind>>,
+) {
+    let Some(mut audio_commands) = audio_commands else { return };
+
+    if !restarts.is_empty() {
+        restarts.clear();
+        // The restarted audio graph no longer has any capture nodes to free
+        for entity in scope_nodes.keys() {
+            if let Some(mut entity_commands) = commands.get_entity(*entity) {
+                entity_commands.remove::<ScopeCapture>();
+            }
+        }
+        scope_nodes.clear();
+        return;
+    }
+
+    let detached: Vec<Entity> = removed_vertices.iter()
+        .chain(changed_kinds.iter())
+        .filter(|entity| scope_nodes.contains_key(entity))
+        .collect();
+    for entity in detached {
+        let Some(address) = scope_nodes.remove(&entity) else { continue };
+        audio_commands.free_node(address);
+        audio_operations.record(AudioOperation::Free, entity);
+        if let Some(mut entity_commands) = commands.get_entity(entity) {
+            entity_commands.remove::<ScopeCapture>();
+        }
+    }
+}
+
+fn attach_scopes(
+    mut commands: Commands,
+    audio_commands: Option<ResMut<AudioCommands>>,
+    audio_nodes: Res<AudioNodes>,
+    mut audio_operations: ResMut<AudioOperations>,
+    mut scope_nodes: ResMut<ScopeNodes>,
+    vertices: Query<(Entity, &NodeKind, Option<&ScopeView>), With<Vertex>>,
+) {
+    let Some(mut audio_commands) = audio_commands else { return };
+
+    for (entity, kind, view) in vertices.iter() {
+        if scope_nodes.contains_key(&entity) || ScopeKind::of(kind).is_none() { continue; }
+        let Some(address) = audio_nodes.get(&entity) else { continue };
+
+        let buffer = Arc::new(ScopeBuffer::new(SCOPE_BUFFER_SIZE));
+        let capture = audio_commands.push(ScopeGen { buffer: buffer.clone() }, inputs!());
+        audio_commands.connect(address.to(&capture).from_index(0).to_index(0));
+        // Errors from a scope's capture node are blamed on the scope
+        audio_operations.record(AudioOperation::Push, entity);
+        audio_operations.record(AudioOperation::Connect, entity);
+        scope_nodes.insert(entity, capture);
+
+        let mut entity_commands = commands.entity(entity);
+        entity_commands.insert(ScopeCapture { buffer });
+        if view.is_none() {
+            entity_commands.insert(ScopeView::default());
+        }
+    }
+}
+
+fn scope_windows(
+    mut contexts: EguiContexts,
+    fft: Res<SpectrumFft>,
+    status: Option<Res<AudioStatus>>,
+    selection: Option<Res<GraphSelection>>,
+    camera: Query<(&Camera, &GlobalTransform), With<PrimaryCamera>>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    mut scopes: Query<(Entity, &VertexName, &NodeKind, &ScopeCapture, &mut ScopeView, &GlobalTransform)>,
+) {
+    let sample_rate = status.map_or(DEFAULT_SAMPLE_RATE, |status| status.sample_rate);
+    let (camera, camera_transform) = camera.single();
+    let Ok(window) = window.get_single() else { return };
+
+    for (entity, name, kind, capture, mut view, transform) in scopes.iter_mut() {
+        let Some(scope_kind) = ScopeKind::of(kind) else { continue };
+        // Selecting a scope brings back its window
+        let reselected = selection.as_ref().map_or(false, |selection| selection.is_changed() && selection.single_vertex() == Some(entity));
+        if reselected {
+            view.open = true;
+        }
+        if !view.open { continue; }
+
+        // Opens beside the vertex. Bevy's viewport origin is the bottom left, egui's is the top left
+        let default_pos = camera.world_to_viewport(camera_transform, transform.translation())
+            .map_or(egui::pos2(0.0, 0.0), |pos| egui::pos2(pos.x + 40.0, window.height() - pos.y));
+
+        let view = &mut *view;
+        let mut open = view.open;
+        egui::Window::new(name.0.as_str())
+            .id(Id::new(("scope", entity)))
+            .default_pos(default_pos)
+            .default_size(egui::vec2(320.0, 180.0))
+            .open(&mut open)
+            .show(contexts.ctx_mut(), |ui| {
+                let samples = capture.buffer.latest();
+                match scope_kind {
+                    ScopeKind::Oscilloscope => {
+                        ui.add(egui::Slider::new(&mut view.trigger, -1.0..=1.0).text("Trigger"));
+                        oscilloscope_plot(ui, entity, &samples, view.trigger, sample_rate);
+                    }
+                    ScopeKind::Spectrum => spectrum_plot(ui, entity, &samples, &*fft.0, sample_rate),
+                }
+            });
+        view.open = open;
+    }
+}
+
+// The start of the latest window that begins with the signal rising through `trigger`
+fn trigger_index(samples: &[f32], trigger: f32) -> Option<usize> {
+    let last_start = samples.len().checked_sub(OSCILLOSCOPE_WINDOW)?;
+    (1..=last_start).rev().find(|i| samples[i - 1] < trigger && samples[*i] >= trigger)
+}
+
+fn oscilloscope_plot(ui: &mut egui::Ui, entity: Entity, samples: &[f32], trigger: f32, sample_rate: usize) {
+    // Free running when the signal never crosses the trigger
+    let start = trigger_index(samples, trigger)
+        .unwrap_or(samples.len().saturating_sub(OSCILLOSCOPE_WINDOW));
+    let end = (start + OSCILLOSCOPE_WINDOW).min(samples.len());
+    let points: PlotPoints = samples[start..end].iter()
+        .enumerate()
+        .map(|(i, sample)| [i as f64 * 1000.0 / sample_rate as f64, *sample as f64])
+        .collect();
+
+    Plot::new(("oscilloscope", entity))
+        .include_y(-1.0)
+        .include_y(1.0)
+        .allow_drag(false)
+        .allow_scroll(false)
+        .x_axis_formatter(|x, _| format!("{x:.1} ms"))
+        .show(ui, |plot| plot.line(Line::new(points)));
+}
+
+fn spectrum_plot(ui: &mut egui::Ui, entity: Entity, samples: &[f32], fft: &dyn Fft<f32>, sample_rate: usize) {
+    let Some(window_start) = samples.len().checked_sub(FFT_SIZE) else {
+        ui.label("Waiting for signal");
+        return;
+    };
+    // Hann window, to stop the edges of the window smearing across the spectrum
+    let mut buffer: Vec<Complex<f32>> = samples[window_start..].iter()
+        .enumerate()
+        .map(|(i, sample)| {
+            let hann = 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / FFT_SIZE as f32).cos();
+            Complex::new(sample * hann, 0.0)
+        })
+        .collect();
+    fft.process(&mut buffer);
+
+    // Scaled so that a full scale sine reads 0 dB
+    let scale = 4.0 / FFT_SIZE as f32;
+    let points: PlotPoints = buffer[1..FFT_SIZE / 2].iter()
+        .enumerate()
+        .map(|(i, bin)| {
+            let frequency = (i + 1) as f64 * sample_rate as f64 / FFT_SIZE as f64;
+            let db = (20.0 * ((bin.norm() * scale) as f64).log10()).max(SPECTRUM_FLOOR_DB);
+            [frequency.log10(), db]
+        })
+        .collect();
+
+    // Frequencies are plotted on a log scale
+    Plot::new(("spectrum", entity))
+        .include_y(SPECTRUM_FLOOR_DB)
+        .include_y(0.0)
+        .allow_drag(false)
+        .allow_scroll(false)
+        .x_axis_formatter(|x, _| format!("{:.0} Hz", 10f64.powf(x)))
+        .y_axis_formatter(|y, _| format!("{y:.0} dB"))
+        .show(ui, |plot| plot.line(Line::new(points)));
+}
@@ -1,13 +1,34 @@
 
-use bevy::prelude::*;
+use std::cmp::Reverse;
+
+use bevy::{prelude::*, window::PrimaryWindow};
 use bevy_egui::{EguiContexts, egui::{self, Id}};
 
-use crate::{AppSet, graph::GraphSelection};
+use crate::{
+    AppSet, AudioSettings, AudioStatus, AudioBackendKind, RestartAudio, output_device_names,
+    camera::{PrimaryCamera, FrameCamera},
+    clipboard::ClipboardCommand,
+    graph::{Graph, GraphSelection, VertexName, VertexBundle, VERTEX_HALF_EXTEND, despawn_selection},
+    helper::LastPrimaryCursorPos,
+    history::{History, Undo, Redo},
+    meter::MeterSettings,
+    midi::{MidiInput, MidiLearn, MidiPort, ConnectMidi, midi_input_port_names},
+    node::{NodeKind, NodeParameters, NodeRegistry, MIN_OUTPUT_CHANNELS},
+    patch::{PatchFile, SavePatch, LoadPatch},
+    perform::ParameterBinding,
+    render::{RenderFile, RenderWav, BitDepth},
+};
 
 const TOP_PANEL_ID: usize = 0;
 const SETTING_PANEL_ID: usize = 1;
 const EDIT_PANEL_ID: usize = 2;
 const SAVE_LOAD_PANEL_ID: usize = 3;
+const NODE_PALETTE_ID: usize = 4;
+const NOTIFICATIONS_ID: usize = 5;
+
+// In seconds
+const NOTIFICATION_DURATION: f64 = 5.0;
+const MAX_NOTIFICATIONS: usize = 5;
 
 #[derive(States, Default, Debug, Clone, Copy, Hash, Eq, PartialEq)]
 pub enum Mode {
@@ -24,6 +45,7 @@ impl Plugin for UiPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.add_state::<Mode>()
             .init_resource::<EguiHover>()
+            .init_resource::<Notifications>()
             .configure_sets((
                 AppSet::UiStartup.in_base_set(StartupSet::Startup),
                 AppSet::Ui.in_base_set(CoreSet::Update),
@@ -37,7 +59,14 @@ impl Plugin for UiPlugin {
                 settings_menu
                     .run_if(state_exists_and_equals(Mode::Settings)),
                 save_load_menu
-                    .run_if(state_exists_and_equals(Mode::SaveLoad))
+                    .run_if(state_exists_and_equals(Mode::SaveLoad)),
+                open_node_palette
+                    .run_if(egui_unfocused)
+                    .run_if(state_exists_and_equals(Mode::Edit)),
+                node_palette
+                    .run_if(resource_exists::<NodePalette>())
+                    .run_if(state_exists_and_equals(Mode::Edit)),
+                show_notifications,
             )
                 .chain()
                 .in_set(AppSet::Ui)
@@ -54,6 +83,11 @@ fn top_menu(
     mut contexts: EguiContexts, 
     mut next_mode: ResMut<NextState<Mode>>,
     mode: Res<State<Mode>>,
+    mut frame_events: EventWriter<FrameCamera>,
+    history: Res<History>,
+    mut undo_events: EventWriter<Undo>,
+    mut redo_events: EventWriter<Redo>,
+    mut meter_settings: ResMut<MeterSettings>,
 ) {
     egui::TopBottomPanel::top(Id::new(TOP_PANEL_ID)).show(contexts.ctx_mut(), |ui| {
         egui::menu::bar(ui, |ui| {
@@ -73,15 +107,149 @@ fn top_menu(
                     let _ = ui.add_enabled(false, egui::Button::new(text));
                 }
             }
+            ui.menu_button("History", |ui| {
+                if ui.add_enabled(history.can_undo(), egui::Button::new("Undo (Ctrl+Z)")).clicked() {
+                    undo_events.send(Undo);
+                    ui.close_menu();
+                }
+                if ui.add_enabled(history.can_redo(), egui::Button::new("Redo (Ctrl+Shift+Z)")).clicked() {
+                    redo_events.send(Redo);
+                    ui.close_menu();
+                }
+            });
+            ui.menu_button("View", |ui| {
+                if ui.button("Frame all (Home)").clicked() {
+                    frame_events.send(FrameCamera::All);
+                    ui.close_menu();
+                }
+                if ui.button("Frame selection (F)").clicked() {
+                    frame_events.send(FrameCamera::Selection);
+                    ui.close_menu();
+                }
+                ui.separator();
+                ui.checkbox(&mut meter_settings.enabled, "Signal meters");
+            });
         });
     });
 }
 
+const SAMPLE_RATES: [u32; 4] = [44100, 48000, 88200, 96000];
+const BLOCK_SIZES: [usize; 6] = [32, 64, 128, 256, 512, 1024];
+
 fn settings_menu(
     mut contexts: EguiContexts,
+    mut settings: ResMut<AudioSettings>,
+    status: Option<Res<AudioStatus>>,
+    mut restart_events: EventWriter<RestartAudio>,
+    mut draft: Local<Option<AudioSettings>>,
+    mut devices: Local<Option<Vec<String>>>,
+    mut message: Local<Option<String>>,
+    midi_input: Res<MidiInput>,
+    mut connect_midi_events: EventWriter<ConnectMidi>,
+    mut midi_ports: Local<Option<Vec<String>>>,
 ) {
+    let draft = draft.get_or_insert_with(|| settings.clone());
+    let devices = devices.get_or_insert_with(output_device_names);
+    let midi_ports = midi_ports.get_or_insert_with(midi_input_port_names);
+
     egui::SidePanel::left(Id::new(SETTING_PANEL_ID)).show(contexts.ctx_mut(), |ui| {
-        ui.label("Settings: TODO");
+        ui.heading("Audio");
+        egui::ComboBox::from_label("Backend")
+            .selected_text(draft.backend.name())
+            .show_ui(ui, |ui| {
+                for backend in AudioBackendKind::ALL {
+                    ui.selectable_value(&mut draft.backend, backend, backend.name());
+                }
+            });
+        egui::ComboBox::from_label("Output device")
+            .selected_text(draft.device.as_str())
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut draft.device, "default".to_string(), "default");
+                for name in devices.iter() {
+                    ui.selectable_value(&mut draft.device, name.clone(), name.as_str());
+                }
+            });
+        if ui.button("Refresh devices").clicked() {
+            *devices = output_device_names();
+        }
+        if draft.backend == AudioBackendKind::Cpal {
+            // The sound card always runs at the device's own rate
+            draft.sample_rate = None;
+            let device_rate = status.as_ref()
+                .filter(|status| status.backend == AudioBackendKind::Cpal)
+                .map_or("Device rate".to_string(), |status| format!("{} Hz (device rate)", status.sample_rate));
+            ui.add_enabled_ui(false, |ui| {
+                egui::ComboBox::from_label("Sample rate")
+                    .selected_text(device_rate)
+                    .show_ui(ui, |_| {});
+            });
+        }
+        else {
+            egui::ComboBox::from_label("Sample rate")
+                .selected_text(match draft.sample_rate {
+                    Some(rate) => format!("{rate} Hz"),
+                    None => "Device default".to_string(),
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut draft.sample_rate, None, "Device default");
+                    for rate in SAMPLE_RATES {
+                        ui.selectable_value(&mut draft.sample_rate, Some(rate), format!("{rate} Hz"));
+                    }
+                });
+        }
+        egui::ComboBox::from_label("Block size")
+            .selected_text(draft.block_size.to_string())
+            .show_ui(ui, |ui| {
+                for block_size in BLOCK_SIZES {
+                    ui.selectable_value(&mut draft.block_size, block_size, block_size.to_string());
+                }
+            });
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut draft.num_outputs).clamp_range(MIN_OUTPUT_CHANNELS..=32));
+            ui.label("Channels");
+        });
+
+        ui.horizontal(|ui| {
+            if ui.add_enabled(*draft != *settings, egui::Button::new("Apply")).clicked() {
+                *settings = draft.clone();
+                *message = settings.save().err().map(|e| format!("Could not save settings: {e}"));
+                restart_events.send(RestartAudio);
+            }
+            if ui.button("Revert").clicked() {
+                *draft = settings.clone();
+            }
+        });
+
+        if let Some(status) = status {
+            ui.label(format!(
+                "{} running at {} Hz, {} samples per block, {} channels",
+                status.backend.name(), status.sample_rate, status.block_size, status.num_outputs
+            ));
+        }
+        if let Some(message) = &*message {
+            ui.label(message);
+        }
+
+        ui.separator();
+        ui.heading("MIDI");
+        let mut port = midi_input.port().clone();
+        egui::ComboBox::from_label("Input port")
+            .selected_text(port.name())
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut port, MidiPort::None, "None");
+                for name in midi_ports.iter() {
+                    ui.selectable_value(&mut port, MidiPort::Device(name.clone()), name.as_str());
+                }
+                if cfg!(unix) {
+                    ui.selectable_value(&mut port, MidiPort::Virtual, MidiPort::Virtual.name());
+                }
+            });
+        if ui.button("Refresh ports").clicked() {
+            *midi_ports = midi_input_port_names();
+        }
+        if port != *midi_input.port() {
+            connect_midi_events.send(ConnectMidi(port));
+        }
     });
 }
 
@@ -89,24 +257,338 @@ fn edit_menu(
     mut commands: Commands,
     mut contexts: EguiContexts,
     selection: Res<GraphSelection>,
+    graph: Res<Graph>,
+    registry: Res<NodeRegistry>,
+    mut clipboard_events: EventWriter<ClipboardCommand>,
+    mut midi_learn: ResMut<MidiLearn>,
+    mut vertices: Query<(&mut VertexName, Option<&mut NodeKind>, Option<&mut NodeParameters>)>,
 ) {
     egui::SidePanel::left(Id::new(EDIT_PANEL_ID)).show(contexts.ctx_mut(), |ui| {
-        ui.label(format!("{:?}", selection));
-        let entity = match *selection { GraphSelection::Edge(e) | GraphSelection::Vertex(e) => e };
-        if ui.button("Delete").clicked() {
-            commands.entity(entity).despawn();
-            commands.remove_resource::<GraphSelection>()
+        if let Some(entity) = selection.single_vertex() {
+            if let Ok((name, kind, parameters)) = vertices.get_mut(entity) {
+                vertex_inspector(ui, &registry, &mut midi_learn, entity, name, kind, parameters);
+            }
+        }
+        else if let Some(entity) = selection.single_edge() {
+            if let Some(edge) = graph.directed_edge(&entity) {
+                let name = |v| vertices.get(v).map_or("?".to_string(), |(name, ..)| name.0.clone());
+                ui.label(format!("{} (output {}) -> {} ({})", name(edge.source), edge.output, name(edge.sink), edge.input));
+            }
+        }
+        else {
+            ui.label(format!("{} nodes and {} edges selected", selection.num_vertices(), selection.num_edges()));
         }
+        ui.horizontal(|ui| {
+            for (text, command) in [
+                ("Copy", ClipboardCommand::Copy),
+                ("Cut", ClipboardCommand::Cut),
+                ("Duplicate", ClipboardCommand::Duplicate),
+            ] {
+                if ui.button(text).clicked() {
+                    clipboard_events.send(command);
+                }
+            }
+            if ui.button("Delete").clicked() {
+                despawn_selection(&mut commands, &graph, &selection);
+            }
+        });
     });
 }
 
+fn vertex_inspector(
+    ui: &mut egui::Ui,
+    registry: &NodeRegistry,
+    midi_learn: &mut MidiLearn,
+    entity: Entity,
+    mut name: Mut<VertexName>,
+    kind: Option<Mut<NodeKind>>,
+    parameters: Option<Mut<NodeParameters>>,
+) {
+    let mut new_name = name.0.clone();
+    ui.horizontal(|ui| {
+        ui.label("Name");
+        ui.text_edit_singleline(&mut new_name);
+    });
+    if new_name != name.0 {
+        name.0 = new_name;
+    }
+
+    let (Some(mut kind), Some(mut parameters)) = (kind, parameters) else { return };
+
+    let mut new_kind = kind.clone();
+    egui::ComboBox::from_label("Node type")
+        .selected_text(new_kind.name())
+        .show_ui(ui, |ui| {
+            for option in registry.iter() {
+                ui.selectable_value(&mut new_kind, option.clone(), option.name());
+            }
+        });
+    if new_kind != *kind {
+        *parameters = NodeParameters::new(&new_kind);
+        *kind = new_kind;
+        return;
+    }
+
+    ui.separator();
+    for (index, input) in kind.inputs().iter().enumerate() {
+        let Some(mut value) = parameters.get(index).copied() else { continue };
+        let spec = kind.parameter_spec(index);
+        let slider = egui::Slider::new(&mut value, spec.range)
+            .logarithmic(spec.logarithmic)
+            .suffix(spec.unit)
+            .text(*input);
+        ui.horizontal(|ui| {
+            if ui.add(slider).changed() {
+                parameters[index] = value;
+            }
+            midi_learn_button(ui, midi_learn, ParameterBinding { vertex: entity, input: index });
+        });
+    }
+}
+
+fn midi_learn_button(ui: &mut egui::Ui, midi_learn: &mut MidiLearn, parameter: ParameterBinding) {
+    let listening = midi_learn.listening == Some(parameter);
+    let text = match midi_learn.controller_of(parameter) {
+        _ if listening => "Move a control...".to_string(),
+        Some(controller) => format!("CC {controller}"),
+        None => "Learn".to_string(),
+    };
+    let response = ui.small_button(text)
+        .on_hover_text("MIDI learn: click, then move a control on the MIDI device. Right-click to unbind");
+    if response.clicked() {
+        midi_learn.listening = if listening { None } else { Some(parameter) };
+    }
+    if response.secondary_clicked() {
+        midi_learn.forget(parameter);
+    }
+}
+
 fn save_load_menu(
     mut contexts: EguiContexts,
+    mut patch_file: ResMut<PatchFile>,
+    mut save_events: EventWriter<SavePatch>,
+    mut load_events: EventWriter<LoadPatch>,
+    mut render_file: ResMut<RenderFile>,
+    mut render_events: EventWriter<RenderWav>,
 ) {
     egui::SidePanel::left(Id::new(SAVE_LOAD_PANEL_ID)).show(contexts.ctx_mut(), |ui| {
-        ui.label("Save/Load: TODO");
+        ui.label("Patch file");
+        ui.text_edit_singleline(&mut patch_file.path);
+        ui.horizontal(|ui| {
+            if ui.button("Save").clicked() {
+                save_events.send(SavePatch(patch_file.path.clone().into()));
+            }
+            if ui.button("Load").clicked() {
+                load_events.send(LoadPatch(patch_file.path.clone().into()));
+            }
+        });
+        if let Some(status) = &patch_file.status {
+            ui.label(status);
+        }
+
+        ui.separator();
+        ui.label("Render to WAV");
+        ui.text_edit_singleline(&mut render_file.path);
+        let options = &mut render_file.options;
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut options.duration).clamp_range(0.0..=600.0).speed(0.1).suffix(" s"));
+            ui.label("Duration");
+        });
+        egui::ComboBox::from_label("Sample rate")
+            .selected_text(format!("{} Hz", options.sample_rate))
+            .show_ui(ui, |ui| {
+                for rate in SAMPLE_RATES {
+                    ui.selectable_value(&mut options.sample_rate, rate as usize, format!("{rate} Hz"));
+                }
+            });
+        egui::ComboBox::from_label("Bit depth")
+            .selected_text(options.bit_depth.name())
+            .show_ui(ui, |ui| {
+                for bit_depth in BitDepth::ALL {
+                    ui.selectable_value(&mut options.bit_depth, bit_depth, bit_depth.name());
+                }
+            });
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut options.num_channels).clamp_range(MIN_OUTPUT_CHANNELS..=32));
+            ui.label("Channels");
+        });
+        if ui.button("Render").clicked() {
+            render_events.send(RenderWav {
+                path: render_file.path.clone().into(),
+                options: render_file.options.clone(),
+            });
+        }
+        if let Some(status) = &render_file.status {
+            ui.label(status);
+        }
+    });
+}
+
+// Exists while the node palette is open
+#[derive(Resource)]
+pub struct NodePalette {
+    world_pos: Vec2,
+    screen_pos: egui::Pos2,
+    search: String,
+    focused: bool,
+}
+
+fn open_node_palette(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mouse: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+    last_cursor_pos: Res<LastPrimaryCursorPos>,
+    camera: Query<(&Camera, &GlobalTransform), With<PrimaryCamera>>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    palette: Option<Res<NodePalette>>,
+) {
+    if palette.is_some() {
+        if mouse.just_pressed(MouseButton::Left) || keys.just_pressed(KeyCode::Escape) {
+            commands.remove_resource::<NodePalette>();
+        }
+        return;
+    }
+    // Don't open while typing a space into a text field
+    let space = keys.just_pressed(KeyCode::Space) && !contexts.ctx_mut().wants_keyboard_input();
+    if !mouse.just_pressed(MouseButton::Right) && !space { return; }
+
+    let Some(cursor_pos) = last_cursor_pos.0 else { return };
+    let (camera, camera_transform) = camera.single();
+    let Some(world_pos) = camera.viewport_to_world_2d(camera_transform, cursor_pos) else { return };
+    let Ok(window) = window.get_single() else { return };
+
+    commands.insert_resource(NodePalette {
+        world_pos,
+        // Bevy's cursor origin is the bottom left of the window, egui's is the top left
+        screen_pos: egui::pos2(cursor_pos.x, window.height() - cursor_pos.y),
+        search: String::new(),
+        focused: false,
     });
+}
 
+fn node_palette(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mut palette: ResMut<NodePalette>,
+    registry: Res<NodeRegistry>,
+) {
+    let palette = &mut *palette;
+    let mut chosen = None;
+    let mut close = false;
+
+    egui::Window::new("Add node")
+        .id(Id::new(NODE_PALETTE_ID))
+        .fixed_pos(palette.screen_pos)
+        .collapsible(false)
+        .resizable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            let response = ui.text_edit_singleline(&mut palette.search);
+            if !palette.focused {
+                response.request_focus();
+                palette.focused = true;
+            }
+
+            let mut matches: Vec<(i32, &NodeKind)> = registry.iter()
+                .filter_map(|kind| {
+                    let score = fuzzy_score(&palette.search, kind.name())
+                        .max(fuzzy_score(&palette.search, kind.category()))?;
+                    Some((score, kind))
+                })
+                .collect();
+            matches.sort_by_key(|(score, kind)| (Reverse(*score), kind.name()));
+
+            for (_, kind) in matches.iter() {
+                if ui.button(format!("{} ({})", kind.name(), kind.category())).clicked() {
+                    chosen = Some((*kind).clone());
+                }
+            }
+            if ui.input(|input| input.key_pressed(egui::Key::Enter)) {
+                chosen = chosen.take().or_else(|| matches.first().map(|(_, kind)| (*kind).clone()));
+            }
+            close = ui.input(|input| input.key_pressed(egui::Key::Escape));
+        });
+
+    if let Some(kind) = chosen {
+        commands.spawn((
+            VertexBundle::new(palette.world_pos.extend(1.0), kind.name(), VERTEX_HALF_EXTEND),
+            kind,
+        ));
+        close = true;
+    }
+    if close {
+        commands.remove_resource::<NodePalette>();
+    }
+}
+
+// Matches the query's characters in order, preferring consecutive matches and matches at word starts
+fn fuzzy_score(query: &str, candidate: &str) -> Option<i32> {
+    let candidate: Vec<char> = candidate.to_lowercase().chars().collect();
+    let mut position = 0;
+    let mut score = 0;
+    for c in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let skipped = candidate[position..].iter().position(|other| *other == c)?;
+        let index = position + skipped;
+        score += 1 - skipped as i32;
+        if index == 0 || !candidate[index - 1].is_alphanumeric() {
+            score += 3;
+        }
+        if skipped == 0 && position > 0 {
+            score += 2;
+        }
+        position = index + 1;
+    }
+    Some(score)
+}
+
+/// Messages shown for a few seconds in the corner of the window.
+#[derive(Resource, Default)]
+pub struct Notifications(Vec<Notification>);
+
+struct Notification {
+    message: String,
+    // The vertex or edge the message is about, highlighted while it's shown
+    subject: Option<Entity>,
+    // Set when first drawn, so that pushing doesn't need the time
+    shown_at: Option<f64>,
+}
+
+impl Notifications {
+    pub fn push(&mut self, message: impl Into<String>) {
+        self.push_about(message, None);
+    }
+
+    pub fn push_about(&mut self, message: impl Into<String>, subject: Option<Entity>) {
+        self.0.push(Notification { message: message.into(), subject, shown_at: None });
+        if self.0.len() > MAX_NOTIFICATIONS {
+            self.0.remove(0);
+        }
+    }
+
+    pub fn is_subject(&self, entity: Entity) -> bool {
+        self.0.iter().any(|notification| notification.subject == Some(entity))
+    }
+}
+
+fn show_notifications(
+    mut contexts: EguiContexts,
+    mut notifications: ResMut<Notifications>,
+    time: Res<Time>,
+) {
+    let now = time.elapsed_seconds_f64();
+    notifications.0.retain(|notification| notification.shown_at.map_or(true, |shown_at| now - shown_at < NOTIFICATION_DURATION));
+    if notifications.0.is_empty() { return; }
+
+    egui::Area::new(Id::new(NOTIFICATIONS_ID))
+        .anchor(egui::Align2::RIGHT_BOTTOM, egui::vec2(-10.0, -10.0))
+        .show(contexts.ctx_mut(), |ui| {
+            for notification in notifications.0.iter_mut() {
+                notification.shown_at.get_or_insert(now);
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.label(&notification.message);
+                });
+            }
+        });
 }
 
 #[derive(Resource, Default)]